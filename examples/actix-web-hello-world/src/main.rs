@@ -4,7 +4,7 @@ extern crate log;
 use std::path::PathBuf;
 
 use actix_web::{web, web::Data, App, HttpRequest, HttpResponse, HttpServer};
-use ssr::{JsRenderer, JsWorkerLog, Ssr, SsrConfig};
+use ssr::{Fallback, JsRenderer, JsWorkerLog, RenderRequest, Ssr, SsrConfig};
 
 #[actix_web::main]
 pub async fn main() -> std::io::Result<()> {
@@ -12,11 +12,16 @@ pub async fn main() -> std::io::Result<()> {
 
     let ssr = Ssr::new(SsrConfig {
         port: 9000,
+        workers: 1,
         js_worker: PathBuf::from("./ssr/js/worker.js"),
         js_worker_log: JsWorkerLog::Verbose,
         global_js_renderer: Some(PathBuf::from(
             "./examples/actix-web-hello-world/src/renderer.js",
         )),
+        fallback: Some(Fallback::new(
+            "./examples/actix-web-hello-world/src/index.html",
+        )),
+        ..SsrConfig::default()
     })
     .await
     .unwrap();
@@ -32,9 +37,28 @@ pub async fn main() -> std::io::Result<()> {
 }
 
 pub async fn hello_world(ssr: Data<Ssr>, req: HttpRequest) -> HttpResponse {
-    let uri = req.uri();
-    match ssr.render(uri, &"Hello, world!", JsRenderer::Global).await {
-        Ok(html) => HttpResponse::Ok().body(html),
+    // actix-web has its own header map type, so headers are copied into `http::HeaderMap`
+    let headers: http::HeaderMap = req
+        .headers()
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    let render_req = RenderRequest {
+        uri: req.uri(),
+        method: Some(req.method()),
+        headers: Some(&headers),
+    };
+    match ssr
+        .render_response(render_req, &"Hello, world!", JsRenderer::Global)
+        .await
+    {
+        Ok(res) => {
+            let mut http_res = HttpResponse::build(res.status);
+            for (name, value) in res.headers.iter() {
+                http_res.header(name, value.clone());
+            }
+            http_res.body(res.body)
+        }
         Err(error) => {
             error!("Error: {}", error);
             HttpResponse::InternalServerError().finish()
@@ -1,4 +1,7 @@
-module.exports.render = ({url, jsonData, hydrationData}) => `
+module.exports.render = ({url, method, headers, cookies, jsonData, hydrationData}) => ({
+  status: 200,
+  headers: {"Cache-Control": "no-cache"},
+  body: `
   <!DOCTYPE html>
   <html>
     <head>
@@ -9,4 +12,5 @@ module.exports.render = ({url, jsonData, hydrationData}) => `
       <div>${jsonData}</div>
     </body>
   </html>
-`;
+`,
+});
@@ -1,15 +1,31 @@
+const fs = require("fs");
 const net = require("net");
+const {SourceMap} = require("module");
+const path = require("path");
+const url = require("url");
+const {PassThrough} = require("stream");
 
 const WORKER_ID = process.pid;
 const ENCODING = "utf8";
 const MESSAGE_LENGTH_BUFFER_SIZE = 4; // 32-bit
+const REQUEST_ID_SIZE = 16; // 128-bit
+const FRAME_HEADER_SIZE = MESSAGE_LENGTH_BUFFER_SIZE + 1 + REQUEST_ID_SIZE;
+const FRAME = {HEAD: 1, CHUNK: 2, END: 3, ERROR: 4, RENDER: 5, CANCEL: 6, PING: 7, PONG: 8};
+// Rust side waits for this line on stdout before dispatching rendering requests to the worker
+const READY_MARKER = "@@ssr-rs:ready";
 
 const env = {
   port: process.env["PORT"],
+  socket: process.env["SOCKET"],
   globalRenderer: process.env["GLOBAL_RENDERER"],
   log: process.env["LOG"],
+  sourceMaps: process.env["SOURCE_MAPS"] === "true",
+  assetManifest: process.env["ASSET_MANIFEST"],
 };
 
+// Updated once the worker binds a port, since it might be ephemeral
+let address = env.socket !== undefined ? `socket: ${env.socket}` : `port: ${env.port}`;
+
 const log = {
   trace:
     function (msg, reqId) {
@@ -25,8 +41,8 @@ const log = {
     function (msg, reqId) {
       const worker =
         !!reqId
-        ? `[JS] Worker [id: ${WORKER_ID} port: ${env.port} request: ${reqId}]`
-        : `[JS] Worker [id: ${WORKER_ID} port: ${env.port}]`;
+        ? `[JS] Worker [id: ${WORKER_ID} ${address} request: ${reqId}]`
+        : `[JS] Worker [id: ${WORKER_ID} ${address}]`;
       const message = msg.charAt(msg.length - 1) === "\n" ? msg : `${msg}\n`;
       process.stderr.write(`${worker}: ${message}`);
     },
@@ -42,8 +58,11 @@ process.on("uncaughtException", (err, origin) => {
 const server = net.createServer();
 
 const port = (() => {
+  if (env.socket !== undefined) {
+    return null;
+  }
   if (env.port === undefined) {
-    log.always("Port is not provided");
+    log.always("Neither port nor socket is provided");
     return process.exit(1);
   }
   const port = parseInt(env.port, 10);
@@ -56,82 +75,402 @@ const port = (() => {
 
 const globalRenderer = env.globalRenderer ? require(env.globalRenderer) : null;
 
-server.on("connection", connection => {
-  log.trace("New connection");
+// Asset manifest of the bundler is loaded once and passed to each render as is
+const assetManifest = env.assetManifest ? JSON.parse(fs.readFileSync(env.assetManifest, ENCODING)) : null;
+
+// Renderer returns either a string with a body or a response object, or a promise of either:
+// {status?, headers?, head?: string | string[], body?, chunks?: string[]}
+// Chunks are keys of the asset manifest used by the page
+// Body is either a string, a buffer, an async iterable (e.g. Readable stream) or a pipeable stream
+const toResponse = output => {
+  if (typeof output === "string") {
+    return {meta: {status: 200, headers: {}, head: ""}, body: output};
+  }
+  if (output === null || typeof output !== "object") {
+    throw new Error(`Renderer returned ${output === null ? "null" : typeof output}, expected a string or a response object`);
+  }
+  const body = output.body === undefined || output.body === null ? "" : output.body;
+  if (!isValidBody(body)) {
+    throw new Error(`Renderer returned a body of type ${typeof body}, expected a string or a stream`);
+  }
+  const head = Array.isArray(output.head) ? output.head.join("") : (output.head || "");
+  const chunks = output.chunks || [];
+  if (!Array.isArray(chunks) || !chunks.every(chunk => typeof chunk === "string")) {
+    throw new Error("Renderer returned invalid chunks, expected an array of strings");
+  }
+  return {
+    meta: {status: output.status || 200, headers: output.headers || {}, head, chunks},
+    body,
+  };
+};
 
-  let metaLength = null;
-  let dataLength = null;
-  let bytesRead = 0;
-  let contents = null;
+const isValidBody = body =>
+  typeof body === "string"
+  || Buffer.isBuffer(body)
+  || (typeof body === "object" && (typeof body[Symbol.asyncIterator] === "function" || typeof body.pipe === "function"));
 
-  connection.on("data", bytes => {
-    log.trace(`New data chunk`);
+const toChunks = body => {
+  if (typeof body === "string" || Buffer.isBuffer(body)) {
+    return [body];
+  }
+  if (typeof body[Symbol.asyncIterator] === "function") {
+    return body;
+  }
+  // Pipeable stream, e.g. returned by React's renderToPipeableStream
+  const chunks = new PassThrough();
+  body.pipe(chunks);
+  return chunks;
+};
+
+// Errors thrown while producing chunks of the body come from the renderer
+async function* rendererChunks(body) {
+  try {
+    yield* toChunks(body);
+  } catch (err) {
+    throw new RendererException(err);
+  }
+}
 
-    try {
-      let chunk = Buffer.from(bytes, ENCODING);
-
-      if (metaLength === null && dataLength === null) {
-        log.trace(`Received initial chunk: ${chunk}`);
-        const metaLengthStartIdx = 0;
-        const dataLengthStartIdx = MESSAGE_LENGTH_BUFFER_SIZE;
-        metaLength = chunk.readUIntBE(metaLengthStartIdx, MESSAGE_LENGTH_BUFFER_SIZE);
-        dataLength = chunk.readUIntBE(dataLengthStartIdx, MESSAGE_LENGTH_BUFFER_SIZE);
-        contents = chunk.slice(dataLengthStartIdx + MESSAGE_LENGTH_BUFFER_SIZE);
-        bytesRead = contents.length;
-      } else {
-        log.trace(`Received subsequent chunk: ${chunk}`);
-        const totalLength = contents.length + chunk.length;
-        contents = Buffer.concat([contents, chunk], totalLength);
-        bytesRead = totalLength;
+// Connections are long-lived and shared by concurrent renders. Each frame is a 32-bit payload
+// length, 8-bit frame type, 128-bit request id and the payload. Rust side sends RENDER frames with
+// rendering requests and CANCEL frames when a response is not needed anymore. Response is a
+// sequence of HEAD with JSON-encoded meta, CHUNKs of the body and END. ERROR with JSON-encoded
+// error can be sent instead of HEAD or at any point after it.
+const encodeFrame = (type, id, payload) => {
+  const header = Buffer.alloc(FRAME_HEADER_SIZE);
+  header.writeUInt32BE(payload.length, 0);
+  header.writeUInt8(type, MESSAGE_LENGTH_BUFFER_SIZE);
+  id.copy(header, MESSAGE_LENGTH_BUFFER_SIZE + 1);
+  return Buffer.concat([header, payload]);
+};
+
+const isClosed = connection => connection.destroyed || !connection.writable;
+
+const drained = connection => new Promise(resolve => {
+  const done = () => {
+    connection.off("drain", done);
+    connection.off("close", done);
+    resolve();
+  };
+  connection.on("drain", done);
+  connection.on("close", done);
+});
+
+// Rust side cancels a render once the render timeout is exceeded or the response is dropped,
+// so by the time rendering is done there might be no one to respond to
+const respond = async (connection, render, output) => {
+  const isCancelled = () => render.cancelled || isClosed(connection);
+  if (isCancelled()) {
+    log.trace("Rendering is cancelled, discarding output", render.reqId);
+    return;
+  }
+  connection.write(encodeFrame(FRAME.HEAD, render.id, Buffer.from(JSON.stringify(output.meta), ENCODING)));
+  for await (const chunk of rendererChunks(output.body)) {
+    if (isCancelled()) {
+      log.trace("Rendering is cancelled, aborting", render.reqId);
+      if (typeof output.body.abort === "function") {
+        output.body.abort();
       }
+      return;
+    }
+    const payload = Buffer.isBuffer(chunk) ? chunk : Buffer.from(chunk, ENCODING);
+    if (payload.length === 0) {
+      continue;
+    }
+    log.trace(`Writing body chunk of ${payload.length} bytes`, render.reqId);
+    if (!connection.write(encodeFrame(FRAME.CHUNK, render.id, payload))) {
+      await drained(connection);
+    }
+  }
+  if (!isCancelled()) {
+    connection.write(encodeFrame(FRAME.END, render.id, Buffer.alloc(0)));
+  }
+};
 
-      log.trace(`Meta length: ${metaLength}`);
-      log.trace(`Data length: ${dataLength}`);
-      log.trace(`Contents: ${contents}`);
-      log.trace(`Bytes read: ${bytesRead}`);
+// Wraps exceptions thrown by a JS renderer to tell them apart from errors of the worker itself
+class RendererException {
+  constructor(error) {
+    this.error = error;
+  }
+}
 
-      if (metaLength + dataLength > bytesRead) {
-        log.trace("Waiting for the next chunk");
-      } else {
-        log.trace("Finished reading data");
-        // We can safely parse meta b/c this is what we get from Rust
-        const meta = JSON.parse(contents.slice(0, metaLength).toString(ENCODING));
+const MAX_CAUSE_DEPTH = 8;
+const OWN_ERROR_PROPERTIES = ["name", "message", "stack", "cause"];
+const STACK_FRAME = /^\s*at (?:(.+?) \((.+?)(?::(\d+):(\d+))?\)|(.+?)(?::(\d+):(\d+))?)$/;
 
-        log.trace(`Parsed meta: ${JSON.stringify(meta)}`, meta.requestId);
+const parseFrames = stack => {
+  if (typeof stack !== "string") return [];
+  const frames = [];
+  for (const line of stack.split("\n")) {
+    const match = STACK_FRAME.exec(line);
+    if (!match) continue;
+    const [, fn, fnFile, fnLine, fnColumn, file, fileLine, fileColumn] = match;
+    const lineNumber = fnLine || fileLine;
+    const columnNumber = fnColumn || fileColumn;
+    frames.push({
+      function: fn || null,
+      file: fnFile || file || null,
+      line: lineNumber ? parseInt(lineNumber, 10) : null,
+      column: columnNumber ? parseInt(columnNumber, 10) : null,
+    });
+  }
+  return frames;
+};
 
-        // However, applying JSON.parse on data is not safe since it might contain
-        // malicious contents, which has been escaped by the renderer
-        // and it would undo the escaping
-        const hydrationData = contents.slice(metaLength, metaLength + dataLength).toString(ENCODING);
+// Custom properties can hold anything, so values that can't be serialized are converted to strings
+const toJson = value => {
+  try {
+    const json = JSON.stringify(value);
+    return json === undefined ? undefined : JSON.parse(json);
+  } catch (err) {
+    return String(value);
+  }
+};
 
-        log.trace(`Hydration data: ${hydrationData}`, meta.requestId);
+// Source maps by a path of a generated file, `null` if there's no source map
+const sourceMaps = new Map();
 
-        const jsonData = JSON.parse(JSON.parse(hydrationData));
+const findSourceMap = file => {
+  if (sourceMaps.has(file)) {
+    return sourceMaps.get(file);
+  }
+  let sourceMap = null;
+  try {
+    const filePath = file.startsWith("file://") ? url.fileURLToPath(file) : file;
+    if (path.isAbsolute(filePath)) {
+      const payload = JSON.parse(fs.readFileSync(`${filePath}.map`, ENCODING));
+      sourceMap = new SourceMap(payload);
+    }
+  } catch (err) {
+    if (err.code !== "ENOENT") {
+      log.always(`Failed to load source map of ${file}: ${err.message}`);
+    }
+  }
+  sourceMaps.set(file, sourceMap);
+  return sourceMap;
+};
 
-        log.trace(`JSON data: ${JSON.stringify(jsonData)}`, meta.requestId);
+const mapFrame = frame => {
+  if (!frame.file || frame.line === null || frame.column === null) return frame;
+  const sourceMap = findSourceMap(frame.file);
+  if (!sourceMap) return frame;
+  const entry = sourceMap.findEntry(frame.line - 1, frame.column - 1);
+  if (!entry || !entry.originalSource) return frame;
+  return {
+    function: frame.function,
+    file: entry.originalSource,
+    line: entry.originalLine + 1,
+    column: entry.originalColumn + 1,
+  };
+};
 
-        const renderer = meta.requestRenderer ? require(meta.requestRenderer) : globalRenderer;
+// Frames and stack of an error reference original sources if source maps are available
+const applySourceMaps = (error, frames) => {
+  const mapped = frames.map(mapFrame);
+  if (mapped.every((frame, idx) => frame === frames[idx])) {
+    return {stack: error.stack, frames};
+  }
+  const lines = mapped.map(frame => {
+    const location = [frame.file || "<anonymous>", frame.line, frame.column].filter(part => part !== null).join(":");
+    return frame.function ? `    at ${frame.function} (${location})` : `    at ${location}`;
+  });
+  return {stack: [`${error.name}: ${error.message}`, ...lines].join("\n"), frames: mapped};
+};
 
-        if (!renderer) {
-          throw new Error(`Renderer is not provided for request ${meta.requestId}`);
-        } else if (!renderer.render) {
-          throw new Error(`Renderer.render function is not defined for request ${meta.requestId}`);
-        }
+const serializeError = (err, origin, depth = 0) => {
+  if (!(err instanceof Error)) {
+    return {name: "Error", message: String(err), stack: null, frames: [], cause: null, properties: {}, origin};
+  }
+  const properties = {};
+  for (const key of Object.keys(err)) {
+    if (OWN_ERROR_PROPERTIES.includes(key)) continue;
+    const value = toJson(err[key]);
+    if (value !== undefined) properties[key] = value;
+  }
+  const cause = err.cause !== undefined && depth < MAX_CAUSE_DEPTH ? serializeError(err.cause, origin, depth + 1) : null;
+  const error = {
+    name: String(err.name),
+    message: String(err.message),
+    stack: typeof err.stack === "string" ? err.stack : null,
+  };
+  const {stack, frames} = env.sourceMaps
+    ? applySourceMaps(error, parseFrames(err.stack))
+    : {stack: error.stack, frames: parseFrames(err.stack)};
+  return {
+    name: error.name,
+    message: error.message,
+    stack,
+    frames,
+    cause,
+    properties,
+    origin,
+  };
+};
+
+const fail = (connection, render, err) => {
+  const error = err instanceof RendererException
+    ? serializeError(err.error, "renderer")
+    : serializeError(err, "worker");
+  log.always(error.stack || `${error.name}: ${error.message}`, render.reqId);
+  if (render.cancelled || isClosed(connection)) {
+    return;
+  }
+  const payload = Buffer.from(JSON.stringify({error}), ENCODING);
+  connection.write(encodeFrame(FRAME.ERROR, render.id, payload));
+};
+
+// Rendering request is a JSON-encoded meta and data, each prefixed with its 32-bit length
+const renderRequest = async (request, reqId) => {
+  const metaLength = request.readUInt32BE(0);
+  const dataLength = request.readUInt32BE(MESSAGE_LENGTH_BUFFER_SIZE);
+  const contents = request.slice(2 * MESSAGE_LENGTH_BUFFER_SIZE);
+
+  log.trace(`Meta length: ${metaLength}`, reqId);
+  log.trace(`Data length: ${dataLength}`, reqId);
+
+  // We can safely parse meta b/c this is what we get from Rust
+  const meta = JSON.parse(contents.slice(0, metaLength).toString(ENCODING));
+
+  log.trace(`Parsed meta: ${JSON.stringify(meta)}`, reqId);
+
+  // Data is JSON with `<`, `>`, `&`, U+2028 and U+2029 escaped by Rust, so it can be inlined into
+  // a script tag as is for hydration. Parsed data is for rendering only, since parsing undoes the
+  // escaping
+  const hydrationData = contents.slice(metaLength, metaLength + dataLength).toString(ENCODING);
+
+  log.trace(`Hydration data: ${hydrationData}`, reqId);
+
+  const jsonData = JSON.parse(hydrationData);
+
+  log.trace(`JSON data: ${JSON.stringify(jsonData)}`, reqId);
+
+  const renderer = meta.requestRenderer ? require(meta.requestRenderer) : globalRenderer;
+
+  if (!renderer) {
+    throw new Error(`Renderer is not provided for request ${reqId}`);
+  } else if (!renderer.render) {
+    throw new Error(`Renderer.render function is not defined for request ${reqId}`);
+  }
+
+  // Async renderers (e.g. ones prefetching data) are awaited, while the render timeout is
+  // enforced on the Rust side
+  let rendered;
+  try {
+    rendered = await renderer.render({
+      url: meta.url,
+      method: meta.method,
+      headers: meta.headers,
+      cookies: meta.cookies,
+      jsonData,
+      hydrationData,
+      manifest: assetManifest,
+    });
+  } catch (err) {
+    throw new RendererException(err);
+  }
+
+  const output = toResponse(rendered);
+
+  log.trace(`Rendered meta: ${JSON.stringify(output.meta)}`, reqId);
+
+  return output;
+};
+
+const formatId = id => {
+  const hex = id.toString("hex");
+  return `${hex.slice(0, 8)}-${hex.slice(8, 12)}-${hex.slice(12, 16)}-${hex.slice(16, 20)}-${hex.slice(20)}`;
+};
 
-        const output = renderer.render({url: meta.url, jsonData, hydrationData});
+// Connections with a function that ends the connection once it has no in-flight renders
+const connections = new Map();
+let shuttingDown = false;
 
-        log.trace(`Rendered output: ${output}`, meta.requestId);
+// Rust side sends SIGTERM on graceful shutdown. Server stops accepting new connections and exits
+// once existing ones are closed, so in-flight renders get a chance to complete.
+process.on("SIGTERM", () => {
+  if (shuttingDown) return;
+  shuttingDown = true;
+  log.always("Shutting down");
+  server.close(() => {
+    log.always("Shut down");
+    process.exit(0);
+  });
+  connections.forEach(endIfIdle => endIfIdle());
+});
+
+server.on("connection", connection => {
+  log.trace("New connection");
+
+  // In-flight renders of this connection by request id
+  const renders = new Map();
+  let buffer = Buffer.alloc(0);
+
+  const endIfIdle = () => {
+    if (renders.size === 0) {
+      connection.end();
+    }
+  };
+  connections.set(connection, endIfIdle);
+
+  const handleFrame = (type, id, payload) => {
+    const key = id.toString("hex");
+    switch (type) {
+      case FRAME.RENDER: {
+        const render = {id, reqId: formatId(id), cancelled: false};
+        renders.set(key, render);
+        const done = () => {
+          renders.delete(key);
+          if (shuttingDown) endIfIdle();
+        };
+        renderRequest(payload, render.reqId)
+          .then(output => respond(connection, render, output))
+          .catch(err => fail(connection, render, err))
+          .finally(done);
+        break;
+      }
+      case FRAME.CANCEL: {
+        const render = renders.get(key);
+        if (render) {
+          log.trace("Cancelling rendering", render.reqId);
+          render.cancelled = true;
+        }
+        break;
+      }
+      case FRAME.PING: {
+        // Pong is sent from the event loop, so a worker blocked by a render stuck in a loop
+        // doesn't respond in time
+        connection.write(encodeFrame(FRAME.PONG, id, Buffer.alloc(0)));
+        break;
+      }
+      default:
+        log.always(`Unknown frame type: ${type}`);
+    }
+  };
+
+  connection.on("data", bytes => {
+    log.trace(`New data chunk`);
 
-        connection.end(Buffer.from(output, "utf8"));
+    buffer = buffer.length === 0 ? bytes : Buffer.concat([buffer, bytes]);
+    while (buffer.length >= FRAME_HEADER_SIZE) {
+      const length = buffer.readUInt32BE(0);
+      if (buffer.length < FRAME_HEADER_SIZE + length) {
+        log.trace("Waiting for the next chunk");
+        break;
       }
-    } catch (err) {
-      log.always(err.stack);
-      connection.end(Buffer.from(`ERROR:${err.stack}`, "utf8"));
+      const type = buffer.readUInt8(MESSAGE_LENGTH_BUFFER_SIZE);
+      const id = Buffer.from(buffer.slice(MESSAGE_LENGTH_BUFFER_SIZE + 1, FRAME_HEADER_SIZE));
+      const payload = buffer.slice(FRAME_HEADER_SIZE, FRAME_HEADER_SIZE + length);
+      buffer = buffer.slice(FRAME_HEADER_SIZE + length);
+      handleFrame(type, id, payload);
     }
   });
 
   connection.once("close", () => {
     log.trace("Connection closed");
+    connections.delete(connection);
+    renders.forEach(render => {
+      render.cancelled = true;
+    });
   });
 
   connection.on("error", err => {
@@ -139,6 +478,36 @@ server.on("connection", connection => {
   });
 });
 
-server.listen(port, () => {
-  log.always(`Ready on port ${server.address().port}`);
+const listen = callback => {
+  if (env.socket === undefined) {
+    return server.listen(port, callback);
+  }
+  // Socket file might be left by a previous process which didn't exit gracefully
+  try {
+    fs.unlinkSync(env.socket);
+  } catch (err) {
+    if (err.code !== "ENOENT") throw err;
+  }
+  // Socket is accessible only by the owner, so other local users can't connect to the worker
+  const umask = process.umask(0o177);
+  try {
+    server.listen(env.socket, () => {
+      fs.chmodSync(env.socket, 0o600);
+      callback();
+    });
+  } finally {
+    process.umask(umask);
+  }
+};
+
+listen(() => {
+  if (env.socket !== undefined) {
+    log.always("Ready");
+    process.stdout.write(`${READY_MARKER} ${JSON.stringify({socket: env.socket})}\n`);
+    return;
+  }
+  const port = server.address().port;
+  address = `port: ${port}`;
+  log.always(`Ready on port ${port}`);
+  process.stdout.write(`${READY_MARKER} ${JSON.stringify({port})}\n`);
 });
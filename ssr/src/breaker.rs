@@ -0,0 +1,400 @@
+// Circuit breaker stops sending renders to a worker which fails most of them, e.g. after a bad
+// deploy of the renderer bundle, so they fail fast instead of paying for a round-trip to the
+// worker. Once the circuit is open for a while, a single render is let through to probe whether
+// the worker has recovered.
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{error::RenderingError, worker::Worker};
+
+/// Configuration of a circuit breaker in front of each worker. See
+/// [`SsrConfig::circuit_breaker`](crate::SsrConfig::circuit_breaker).
+///
+/// Renders failed due to a JS exception, an invalid response, a timeout or a broken connection
+/// are considered failures. Once the share of failed renders within `window` reaches
+/// `failure_threshold`, the circuit opens and renders dispatched to the worker fail right away
+/// with [`RenderingError::CircuitOpen`](crate::RenderingError::CircuitOpen). After
+/// `open_duration`, a single render is let through: if it succeeds, the circuit closes, otherwise
+/// it stays open for another `open_duration`.
+#[derive(Clone, Copy, Debug)]
+pub struct CircuitBreakerConfig {
+    /// A share of failed renders, from `0.0` to `1.0`, which opens the circuit.
+    pub failure_threshold: f64,
+    /// A min number of renders within `window` before the circuit can open, so a couple of
+    /// failures right after startup don't open it.
+    pub min_renders: usize,
+    /// A period over which the share of failed renders is calculated.
+    pub window: Duration,
+    /// How long the circuit stays open before a render is let through to probe the worker.
+    pub open_duration: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 0.5,
+            min_renders: 20,
+            window: Duration::from_secs(10),
+            open_duration: Duration::from_secs(5),
+        }
+    }
+}
+
+enum State {
+    Closed {
+        since: Instant,
+        renders: usize,
+        failures: usize,
+    },
+    Open {
+        until: Instant,
+    },
+    // A single probing render is allowed at a time.
+    HalfOpen {
+        probing: bool,
+    },
+}
+
+impl State {
+    fn closed(now: Instant) -> Self {
+        State::Closed {
+            since: now,
+            renders: 0,
+            failures: 0,
+        }
+    }
+}
+
+pub(crate) struct Breaker {
+    cfg: CircuitBreakerConfig,
+    state: Mutex<State>,
+}
+
+impl Breaker {
+    pub fn new(cfg: CircuitBreakerConfig) -> Self {
+        Self {
+            cfg,
+            state: Mutex::new(State::closed(Instant::now())),
+        }
+    }
+
+    // Whether the circuit is open and it's not time to probe the worker yet.
+    pub fn is_open(&self) -> bool {
+        self.is_open_at(Instant::now())
+    }
+
+    fn is_open_at(&self, now: Instant) -> bool {
+        match &*self.state.lock().expect("Breaker lock is poisoned") {
+            State::Closed { .. } => false,
+            State::Open { until } => now < *until,
+            State::HalfOpen { probing } => *probing,
+        }
+    }
+
+    // Returns `None` if renders are not allowed through the circuit at the moment.
+    pub fn attempt(self: &Arc<Self>) -> Option<Attempt> {
+        self.attempt_at(Instant::now())
+    }
+
+    fn attempt_at(self: &Arc<Self>, now: Instant) -> Option<Attempt> {
+        let mut state = self.state.lock().expect("Breaker lock is poisoned");
+        let probe = match &mut *state {
+            State::Closed { .. } => false,
+            State::Open { until } if now < *until => return None,
+            State::Open { .. } => {
+                *state = State::HalfOpen { probing: true };
+                true
+            }
+            State::HalfOpen { probing: true } => return None,
+            State::HalfOpen { probing } => {
+                *probing = true;
+                true
+            }
+        };
+        Some(Attempt {
+            breaker: self.clone(),
+            probe,
+            finished: false,
+        })
+    }
+
+    fn record(&self, worker: &dyn fmt::Display, probe: bool, failed: bool, now: Instant) {
+        let mut state = self.state.lock().expect("Breaker lock is poisoned");
+        if probe {
+            if failed {
+                warn!(
+                    "{worker}: Probing render failed, circuit stays open",
+                    worker = worker
+                );
+                *state = State::Open {
+                    until: now + self.cfg.open_duration,
+                };
+            } else {
+                info!(
+                    "{worker}: Probing render succeeded, circuit is closed",
+                    worker = worker
+                );
+                *state = State::closed(now);
+            }
+            return;
+        }
+
+        // Results of renders which started before the circuit opened are ignored
+        if let State::Closed {
+            since,
+            renders,
+            failures,
+        } = &mut *state
+        {
+            if now.saturating_duration_since(*since) > self.cfg.window {
+                *since = now;
+                *renders = 0;
+                *failures = 0;
+            }
+            *renders += 1;
+            if failed {
+                *failures += 1;
+            }
+            let rate = *failures as f64 / *renders as f64;
+            if *renders >= self.cfg.min_renders && rate >= self.cfg.failure_threshold {
+                warn!(
+                    "{worker}: {failures} of {renders} renders failed, circuit is open for {duration}ms",
+                    worker = worker,
+                    failures = failures,
+                    renders = renders,
+                    duration = self.cfg.open_duration.as_millis()
+                );
+                *state = State::Open {
+                    until: now + self.cfg.open_duration,
+                };
+            }
+        }
+    }
+
+    fn abandon_probe(&self) {
+        let mut state = self.state.lock().expect("Breaker lock is poisoned");
+        if let State::HalfOpen { probing } = &mut *state {
+            *probing = false;
+        }
+    }
+}
+
+// A render let through the circuit. If it's dropped before the result is known, e.g. because
+// the render was cancelled, it doesn't count, and the next render probes the worker instead.
+pub(crate) struct Attempt {
+    breaker: Arc<Breaker>,
+    probe: bool,
+    finished: bool,
+}
+
+impl Attempt {
+    pub fn succeed(self, worker: &Worker) {
+        self.finish(worker, false, Instant::now());
+    }
+
+    pub fn fail(self, worker: &Worker, err: &RenderingError) {
+        if Self::counts(err) {
+            self.finish(worker, true, Instant::now());
+        }
+    }
+
+    fn finish(mut self, worker: &dyn fmt::Display, failed: bool, now: Instant) {
+        self.finished = true;
+        self.breaker.record(worker, self.probe, failed, now);
+    }
+
+    // Only failures which indicate that the worker is unhealthy count.
+    fn counts(err: &RenderingError) -> bool {
+        match err {
+            RenderingError::ConnectionError(_)
+            | RenderingError::RenderRequestError(_)
+            | RenderingError::RenderResponseError(_)
+            | RenderingError::JsExceptionDuringRendering(_)
+            | RenderingError::InvalidResponse(_)
+            | RenderingError::Timeout(_) => true,
+            RenderingError::WorkerIsUnavailable
+            | RenderingError::InvalidUri
+            | RenderingError::GlobalRendererNotProvided
+            | RenderingError::UrlSerializationError(_)
+            | RenderingError::DataSerializationError(_)
+            | RenderingError::ShuttingDown
+            | RenderingError::Overloaded
+            | RenderingError::CircuitOpen
+            | RenderingError::BacklogExceeded(_) => false,
+        }
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        if self.probe && !self.finished {
+            self.breaker.abandon_probe();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    const WORKER: &str = "[RS] Worker";
+
+    fn breaker() -> Arc<Breaker> {
+        Arc::new(Breaker::new(CircuitBreakerConfig {
+            failure_threshold: 0.5,
+            min_renders: 4,
+            window: Duration::from_secs(10),
+            open_duration: Duration::from_secs(5),
+        }))
+    }
+
+    fn at(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    // Lets a render through the circuit and records its result.
+    fn render(breaker: &Arc<Breaker>, failed: bool, now: Instant) {
+        let attempt = breaker.attempt_at(now).expect("Circuit is open");
+        attempt.finish(&WORKER, failed, now);
+    }
+
+    // Renders until the circuit opens.
+    fn open(breaker: &Arc<Breaker>, now: Instant) {
+        for _ in 0..4 {
+            render(breaker, true, now);
+        }
+        assert!(breaker.is_open_at(now));
+    }
+
+    #[test]
+    fn stays_closed_until_min_renders() {
+        let breaker = breaker();
+        let start = Instant::now();
+        for _ in 0..3 {
+            render(&breaker, true, start);
+        }
+        assert!(!breaker.is_open_at(start));
+        render(&breaker, true, start);
+        assert!(breaker.is_open_at(start));
+        assert!(breaker.attempt_at(start).is_none());
+    }
+
+    #[test]
+    fn opens_once_failure_threshold_is_reached() {
+        let breaker = breaker();
+        let start = Instant::now();
+        render(&breaker, false, start);
+        render(&breaker, false, start);
+        render(&breaker, false, start);
+        render(&breaker, true, start);
+        render(&breaker, true, start);
+        assert!(!breaker.is_open_at(start));
+        render(&breaker, true, start);
+        assert!(breaker.is_open_at(start));
+    }
+
+    #[test]
+    fn starts_over_once_window_rolls_over() {
+        let breaker = breaker();
+        let start = Instant::now();
+        for _ in 0..3 {
+            render(&breaker, true, start);
+        }
+        // Failures of the previous window don't count
+        let next = at(start, 10_001);
+        render(&breaker, true, next);
+        assert!(!breaker.is_open_at(next));
+        for _ in 0..3 {
+            render(&breaker, false, next);
+        }
+        render(&breaker, true, next);
+        assert!(!breaker.is_open_at(next));
+    }
+
+    #[test]
+    fn probes_worker_once_open_duration_is_over() {
+        let breaker = breaker();
+        let start = Instant::now();
+        open(&breaker, start);
+        assert!(breaker.attempt_at(at(start, 4_999)).is_none());
+
+        let probing = at(start, 5_000);
+        assert!(!breaker.is_open_at(probing));
+        let probe = breaker
+            .attempt_at(probing)
+            .expect("Probe is not let through");
+        assert!(probe.probe);
+        // Only a single render probes the worker at a time
+        assert!(breaker.is_open_at(probing));
+        assert!(breaker.attempt_at(probing).is_none());
+
+        probe.finish(&WORKER, false, probing);
+        assert!(!breaker.is_open_at(probing));
+        let attempt = breaker.attempt_at(probing).unwrap();
+        assert!(!attempt.probe);
+    }
+
+    #[test]
+    fn reopens_once_probe_fails() {
+        let breaker = breaker();
+        let start = Instant::now();
+        open(&breaker, start);
+
+        let probing = at(start, 5_000);
+        let probe = breaker.attempt_at(probing).unwrap();
+        probe.finish(&WORKER, true, probing);
+        assert!(breaker.is_open_at(at(start, 9_999)));
+        assert!(breaker.attempt_at(at(start, 9_999)).is_none());
+        assert!(breaker.attempt_at(at(start, 10_000)).unwrap().probe);
+    }
+
+    #[test]
+    fn abandoned_probe_lets_next_render_probe() {
+        let breaker = breaker();
+        let start = Instant::now();
+        open(&breaker, start);
+
+        let probing = at(start, 5_000);
+        drop(breaker.attempt_at(probing).unwrap());
+        assert!(!breaker.is_open_at(probing));
+        let probe = breaker.attempt_at(probing).unwrap();
+        assert!(probe.probe);
+        probe.finish(&WORKER, false, probing);
+        assert!(!breaker.is_open_at(probing));
+    }
+
+    #[test]
+    fn ignores_renders_started_before_circuit_opened() {
+        let breaker = breaker();
+        let start = Instant::now();
+        let late = breaker.attempt_at(start).unwrap();
+        open(&breaker, start);
+        late.finish(&WORKER, false, start);
+        assert!(breaker.is_open_at(start));
+    }
+
+    #[test]
+    fn counts_only_failures_of_worker() {
+        let err = |kind| io::Error::new(kind, "closed");
+        assert!(Attempt::counts(&RenderingError::Timeout(
+            Duration::from_secs(1)
+        )));
+        assert!(Attempt::counts(&RenderingError::InvalidResponse(
+            String::new()
+        )));
+        assert!(Attempt::counts(&RenderingError::ConnectionError(err(
+            io::ErrorKind::ConnectionRefused
+        ))));
+        assert!(!Attempt::counts(&RenderingError::Overloaded));
+        assert!(!Attempt::counts(&RenderingError::CircuitOpen));
+        assert!(!Attempt::counts(&RenderingError::InvalidUri));
+        assert!(!Attempt::counts(&RenderingError::ShuttingDown));
+        assert!(!Attempt::counts(&RenderingError::BacklogExceeded(1)));
+    }
+}
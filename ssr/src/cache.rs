@@ -0,0 +1,608 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap},
+    hash::BuildHasher,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use http::header::{self, HeaderMap};
+use tokio::sync::watch;
+
+use crate::{error::RenderingError, response::RenderResponse};
+
+/// Configuration of the in-memory cache of rendered responses. See
+/// [`SsrConfig::cache`](crate::SsrConfig::cache).
+///
+/// The least recently used responses are evicted once either of the limits is exceeded.
+///
+/// Concurrent renders of the same request which is not cached yet are coalesced: only one of them
+/// is sent to a worker, while the rest wait for its response.
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// Maximum number of cached responses.
+    pub max_entries: usize,
+    /// Maximum total size of cached responses in bytes.
+    pub max_bytes: usize,
+    /// How long a response stays fresh after it's rendered.
+    pub ttl: Duration,
+    /// How long after `ttl` is exceeded a stale response can still be served. During this time,
+    /// the first request for the stale response triggers its rendering in the background, while
+    /// the stale response is served until the fresh one replaces it. `None` means expired
+    /// responses are never served.
+    pub stale_while_revalidate: Option<Duration>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 1000,
+            max_bytes: 64 * 1024 * 1024,
+            ttl: Duration::from_secs(60),
+            stale_while_revalidate: None,
+        }
+    }
+}
+
+// A hash of a serialized rendering request: url, renderer, forwarded method, headers and cookies,
+// and data. The request is controlled by clients, so it's hashed with two SipHash keys which are
+// random per cache. Otherwise, a client could craft a request colliding with another page and get
+// its response cached for that page.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub(crate) struct Key(u64, u64);
+
+struct Entry {
+    response: RenderResponse,
+    // Path and query of the rendered request, used for invalidation.
+    url: String,
+    size: usize,
+    rendered_at: Instant,
+    last_used: u64,
+}
+
+// An outcome of a render shared with coalesced ones. `None` until the render is complete.
+type Outcome = Option<Shared>;
+
+#[derive(Clone)]
+enum Shared {
+    Response(RenderResponse),
+    // The response is specific to the request, so coalesced renders render their own.
+    Private,
+    Error(Arc<RenderingError>),
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<Key, Entry>,
+    // Keys by the tick of their last use, from the least recently used one.
+    recency: BTreeMap<u64, Key>,
+    // Renders in progress by their keys, along with the tick they started at.
+    flights: HashMap<Key, (u64, watch::Receiver<Outcome>)>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl State {
+    fn remove(&mut self, key: &Key) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.last_used);
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    // Marks the entry as the most recently used one.
+    fn touch(&mut self, key: &Key) -> RenderResponse {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key).expect("Cache entry is missing");
+        let last_used = entry.last_used;
+        entry.last_used = tick;
+        let response = entry.response.clone();
+        self.recency.remove(&last_used);
+        self.recency.insert(tick, *key);
+        response
+    }
+}
+
+pub(crate) enum Lookup {
+    Fresh(RenderResponse),
+    // A stale response. If it's not being refreshed yet, the caller is supposed to refresh it
+    // using the returned flight.
+    Stale(RenderResponse, Option<Flight>),
+    // The same request is being rendered by another caller.
+    Pending(Pending),
+    // The caller is supposed to render the response and complete the returned flight.
+    Miss(Flight),
+}
+
+pub(crate) struct Cache {
+    cfg: CacheConfig,
+    state: Mutex<State>,
+    hashers: (RandomState, RandomState),
+}
+
+impl Cache {
+    pub fn new(cfg: CacheConfig) -> Self {
+        Self {
+            cfg,
+            state: Mutex::new(State::default()),
+            hashers: (RandomState::new(), RandomState::new()),
+        }
+    }
+
+    pub fn key(&self, input: &[u8]) -> Key {
+        Key(
+            self.hashers.0.hash_one(input),
+            self.hashers.1.hash_one(input),
+        )
+    }
+
+    pub fn lookup(self: &Arc<Self>, key: &Key) -> Lookup {
+        let mut state = self.state.lock().expect("Cache lock is poisoned");
+        let now = Instant::now();
+        let age = state
+            .entries
+            .get(key)
+            .map(|entry| now.saturating_duration_since(entry.rendered_at));
+        let stale_ttl = self.cfg.ttl + self.cfg.stale_while_revalidate.unwrap_or_default();
+        match age {
+            Some(age) if age < self.cfg.ttl => Lookup::Fresh(state.touch(key)),
+            Some(age) if age < stale_ttl => {
+                let response = state.touch(key);
+                let flight = if state.flights.contains_key(key) {
+                    None
+                } else {
+                    Some(self.start_flight(&mut state, *key))
+                };
+                Lookup::Stale(response, flight)
+            }
+            _ => {
+                state.remove(key);
+                match state.flights.get(key) {
+                    Some((_, outcome)) => Lookup::Pending(Pending(outcome.clone())),
+                    None => Lookup::Miss(self.start_flight(&mut state, *key)),
+                }
+            }
+        }
+    }
+
+    fn start_flight(self: &Arc<Self>, state: &mut State, key: Key) -> Flight {
+        let id = state.next_tick();
+        let (sender, outcome) = watch::channel(None);
+        state.flights.insert(key, (id, outcome));
+        Flight {
+            cache: self.clone(),
+            key,
+            id,
+            sender,
+        }
+    }
+
+    // Only successful responses which are not marked as private or non-storable by JS renderer,
+    // and don't set cookies, get cached. A cookie, e.g. a session id, is specific to the user it's
+    // set for, so a cached response would hand it over to everyone else.
+    fn insert(&self, state: &mut State, key: Key, url: String, response: &RenderResponse) {
+        if !Self::is_cacheable(response) {
+            return;
+        }
+        let size = Self::size(&url, response);
+        if size > self.cfg.max_bytes || self.cfg.max_entries == 0 {
+            return;
+        }
+        state.remove(&key);
+        while state.entries.len() >= self.cfg.max_entries || state.bytes + size > self.cfg.max_bytes
+        {
+            let lru = match state.recency.values().next() {
+                Some(key) => *key,
+                None => break,
+            };
+            state.remove(&lru);
+        }
+        let tick = state.next_tick();
+        state.bytes += size;
+        state.recency.insert(tick, key);
+        state.entries.insert(
+            key,
+            Entry {
+                response: response.clone(),
+                url,
+                size,
+                rendered_at: Instant::now(),
+                last_used: tick,
+            },
+        );
+    }
+
+    pub fn invalidate<F>(&self, predicate: F)
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut state = self.state.lock().expect("Cache lock is poisoned");
+        let keys: Vec<Key> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| predicate(&entry.url))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            state.remove(&key);
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state.lock().expect("Cache lock is poisoned");
+        state.entries.clear();
+        state.recency.clear();
+        state.bytes = 0;
+    }
+
+    fn is_cacheable(response: &RenderResponse) -> bool {
+        response.status.is_success()
+            && !Self::is_private(response)
+            && !Self::has_directive(&response.headers, "no-store")
+    }
+
+    // Private responses are not shared with anyone but the request they're rendered for, including
+    // coalesced renders.
+    fn is_private(response: &RenderResponse) -> bool {
+        response.headers.contains_key(header::SET_COOKIE)
+            || Self::has_directive(&response.headers, "private")
+    }
+
+    fn has_directive(headers: &HeaderMap, name: &str) -> bool {
+        headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|directive| directive.trim())
+            .any(|directive| directive.eq_ignore_ascii_case(name))
+    }
+
+    fn size(url: &str, response: &RenderResponse) -> usize {
+        let headers: usize = response
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        url.len() + response.head.len() + response.body.len() + headers
+    }
+}
+
+// A render in progress. Once it's complete, its response gets cached and shared with coalesced
+// renders. If the flight is dropped before completion, e.g. because the caller is gone or timed
+// out, coalesced renders get notified and one of them starts a new flight.
+pub(crate) struct Flight {
+    cache: Arc<Cache>,
+    key: Key,
+    id: u64,
+    sender: watch::Sender<Outcome>,
+}
+
+impl Flight {
+    pub fn complete(
+        self,
+        url: String,
+        result: Result<RenderResponse, RenderingError>,
+    ) -> Result<RenderResponse, RenderingError> {
+        let outcome = match &result {
+            Ok(response) if Cache::is_private(response) => Shared::Private,
+            Ok(response) => {
+                let mut state = self.cache.state.lock().expect("Cache lock is poisoned");
+                self.cache.insert(&mut state, self.key, url, response);
+                Shared::Response(response.clone())
+            }
+            // Timeout depends on the deadline of the caller, so coalesced renders start over
+            // once the flight is dropped instead.
+            Err(RenderingError::Timeout(_)) => return result,
+            Err(err) => Shared::Error(Arc::new(err.replicate())),
+        };
+        self.sender.broadcast(Some(outcome)).ok();
+        result
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        let mut state = self.cache.state.lock().expect("Cache lock is poisoned");
+        if let Some((id, _)) = state.flights.get(&self.key) {
+            if *id == self.id {
+                state.flights.remove(&self.key);
+            }
+        }
+    }
+}
+
+// A render coalesced with the one in progress.
+pub(crate) struct Pending(watch::Receiver<Outcome>);
+
+pub(crate) enum Coalesced {
+    Done(Result<RenderResponse, RenderingError>),
+    // The response of the render in progress is private, so the caller renders its own.
+    Private,
+    // The render in progress was abandoned, so the caller starts over.
+    Abandoned,
+}
+
+impl Pending {
+    pub async fn wait(mut self) -> Coalesced {
+        while let Some(outcome) = self.0.recv().await {
+            match outcome {
+                Some(Shared::Response(response)) => return Coalesced::Done(Ok(response)),
+                Some(Shared::Private) => return Coalesced::Private,
+                Some(Shared::Error(err)) => return Coalesced::Done(Err(err.replicate())),
+                None => continue,
+            }
+        }
+        Coalesced::Abandoned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderValue, StatusCode};
+
+    use super::*;
+
+    fn cache(cfg: CacheConfig) -> Arc<Cache> {
+        Arc::new(Cache::new(cfg))
+    }
+
+    fn response(body: &str) -> RenderResponse {
+        RenderResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            head: String::new(),
+            body: body.to_string(),
+        }
+    }
+
+    fn with_header(name: header::HeaderName, value: &'static str) -> RenderResponse {
+        let mut res = response("body");
+        res.headers.append(name, HeaderValue::from_static(value));
+        res
+    }
+
+    // Renders the response as if a worker returned it.
+    fn render(cache: &Arc<Cache>, url: &str, res: RenderResponse) {
+        match cache.lookup(&cache.key(url.as_bytes())) {
+            Lookup::Miss(flight) | Lookup::Stale(_, Some(flight)) => {
+                flight.complete(url.to_string(), Ok(res)).unwrap();
+            }
+            _ => panic!("{} is not expected to be rendered", url),
+        }
+    }
+
+    fn cached(cache: &Arc<Cache>, url: &str) -> Option<String> {
+        match cache.lookup(&cache.key(url.as_bytes())) {
+            Lookup::Fresh(res) => Some(res.body),
+            _ => None,
+        }
+    }
+
+    fn is_cached(cache: &Arc<Cache>, url: &str) -> bool {
+        cache
+            .state
+            .lock()
+            .unwrap()
+            .entries
+            .contains_key(&cache.key(url.as_bytes()))
+    }
+
+    // Moves the time the response was rendered at into the past.
+    fn age(cache: &Arc<Cache>, url: &str, by: Duration) {
+        let mut state = cache.state.lock().unwrap();
+        let entry = state.entries.get_mut(&cache.key(url.as_bytes())).unwrap();
+        entry.rendered_at = entry.rendered_at.checked_sub(by).unwrap();
+    }
+
+    #[test]
+    fn keys_are_specific_to_cache() {
+        let first = cache(CacheConfig::default());
+        let second = cache(CacheConfig::default());
+        assert_eq!(first.key(b"/a"), first.key(b"/a"));
+        assert_ne!(first.key(b"/a"), first.key(b"/b"));
+        assert_ne!(first.key(b"/a"), second.key(b"/a"));
+    }
+
+    #[test]
+    fn serves_cached_response() {
+        let cache = cache(CacheConfig::default());
+        render(&cache, "/a", response("a"));
+        assert_eq!(cached(&cache, "/a").as_deref(), Some("a"));
+        assert_eq!(cached(&cache, "/b"), None);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_entries() {
+        let cache = cache(CacheConfig {
+            max_entries: 2,
+            ..CacheConfig::default()
+        });
+        render(&cache, "/a", response("a"));
+        render(&cache, "/b", response("b"));
+        // `/a` becomes the most recently used one
+        assert!(cached(&cache, "/a").is_some());
+        render(&cache, "/c", response("c"));
+        assert!(is_cached(&cache, "/a"));
+        assert!(!is_cached(&cache, "/b"));
+        assert!(is_cached(&cache, "/c"));
+
+        render(&cache, "/d", response("d"));
+        assert!(!is_cached(&cache, "/a"));
+        assert!(is_cached(&cache, "/c"));
+        assert!(is_cached(&cache, "/d"));
+    }
+
+    #[test]
+    fn accounts_size_of_entries() {
+        let size = Cache::size("/a", &response("aaaa"));
+        assert_eq!(size, 6);
+        let cache = cache(CacheConfig {
+            max_bytes: 2 * size,
+            ..CacheConfig::default()
+        });
+        render(&cache, "/a", response("aaaa"));
+        render(&cache, "/b", response("bbbb"));
+        assert_eq!(cache.state.lock().unwrap().bytes, 2 * size);
+
+        cache.invalidate(|url| url == "/b");
+        assert_eq!(cache.state.lock().unwrap().bytes, size);
+        render(&cache, "/b", response("bb"));
+        assert_eq!(cache.state.lock().unwrap().bytes, size + 4);
+
+        // The least recently used entries are evicted until the new one fits
+        render(&cache, "/c", response("cccccc"));
+        assert!(!is_cached(&cache, "/a"));
+        assert!(is_cached(&cache, "/b"));
+        assert!(is_cached(&cache, "/c"));
+        assert_eq!(cache.state.lock().unwrap().bytes, 4 + 8);
+
+        // An entry which doesn't fit at all is not cached and doesn't evict anything
+        render(&cache, "/d", response(&"d".repeat(2 * size)));
+        assert!(!is_cached(&cache, "/d"));
+        assert!(is_cached(&cache, "/c"));
+
+        cache.clear();
+        assert_eq!(cache.state.lock().unwrap().bytes, 0);
+        assert!(cache.state.lock().unwrap().recency.is_empty());
+    }
+
+    #[test]
+    fn expires_entries_after_ttl() {
+        let cache = cache(CacheConfig {
+            ttl: Duration::from_secs(10),
+            ..CacheConfig::default()
+        });
+        render(&cache, "/a", response("a"));
+        age(&cache, "/a", Duration::from_secs(9));
+        assert!(cached(&cache, "/a").is_some());
+        age(&cache, "/a", Duration::from_secs(1));
+        match cache.lookup(&cache.key("/a".as_bytes())) {
+            Lookup::Miss(_) => {}
+            _ => panic!("Expired response is served"),
+        }
+        assert!(!is_cached(&cache, "/a"));
+    }
+
+    #[test]
+    fn serves_stale_entries_while_revalidating() {
+        let cache = cache(CacheConfig {
+            ttl: Duration::from_secs(10),
+            stale_while_revalidate: Some(Duration::from_secs(5)),
+            ..CacheConfig::default()
+        });
+        render(&cache, "/a", response("old"));
+        age(&cache, "/a", Duration::from_secs(12));
+
+        // The first request refreshes the response, while the rest get the stale one meanwhile
+        let flight = match cache.lookup(&cache.key("/a".as_bytes())) {
+            Lookup::Stale(res, Some(flight)) => {
+                assert_eq!(res.body, "old");
+                flight
+            }
+            _ => panic!("Stale response is not served"),
+        };
+        match cache.lookup(&cache.key("/a".as_bytes())) {
+            Lookup::Stale(res, None) => assert_eq!(res.body, "old"),
+            _ => panic!("Stale response is not served"),
+        }
+        flight
+            .complete("/a".to_string(), Ok(response("new")))
+            .unwrap();
+        assert_eq!(cached(&cache, "/a").as_deref(), Some("new"));
+
+        // Once the stale window is over, the response is rendered again
+        age(&cache, "/a", Duration::from_secs(15));
+        match cache.lookup(&cache.key("/a".as_bytes())) {
+            Lookup::Miss(_) => {}
+            _ => panic!("Expired response is served"),
+        }
+    }
+
+    #[test]
+    fn coalesces_renders_of_the_same_request() {
+        let cache = cache(CacheConfig::default());
+        let flight = match cache.lookup(&cache.key("/a".as_bytes())) {
+            Lookup::Miss(flight) => flight,
+            _ => panic!("Response is cached"),
+        };
+        assert!(matches!(
+            cache.lookup(&cache.key("/a".as_bytes())),
+            Lookup::Pending(_)
+        ));
+        drop(flight);
+        assert!(matches!(
+            cache.lookup(&cache.key("/a".as_bytes())),
+            Lookup::Miss(_)
+        ));
+    }
+
+    #[test]
+    fn invalidates_entries_by_url() {
+        let cache = cache(CacheConfig::default());
+        render(&cache, "/posts/1", response("1"));
+        render(&cache, "/posts/2", response("2"));
+        render(&cache, "/about", response("about"));
+        cache.invalidate(|url| url.starts_with("/posts/"));
+        assert!(!is_cached(&cache, "/posts/1"));
+        assert!(!is_cached(&cache, "/posts/2"));
+        assert!(is_cached(&cache, "/about"));
+        let state = cache.state.lock().unwrap();
+        assert_eq!(state.recency.len(), 1);
+        assert_eq!(state.bytes, Cache::size("/about", &response("about")));
+    }
+
+    #[test]
+    fn does_not_cache_responses_specific_to_request() {
+        let cache = cache(CacheConfig::default());
+        render(
+            &cache,
+            "/cookie",
+            with_header(header::SET_COOKIE, "session=user1"),
+        );
+        render(
+            &cache,
+            "/private",
+            with_header(header::CACHE_CONTROL, "max-age=60, Private"),
+        );
+        render(
+            &cache,
+            "/no-store",
+            with_header(header::CACHE_CONTROL, "no-store"),
+        );
+        let mut not_found = response("not found");
+        not_found.status = StatusCode::NOT_FOUND;
+        render(&cache, "/404", not_found);
+        render(
+            &cache,
+            "/public",
+            with_header(header::CACHE_CONTROL, "public, max-age=60"),
+        );
+        assert!(!is_cached(&cache, "/cookie"));
+        assert!(!is_cached(&cache, "/private"));
+        assert!(!is_cached(&cache, "/no-store"));
+        assert!(!is_cached(&cache, "/404"));
+        assert!(is_cached(&cache, "/public"));
+    }
+
+    #[tokio::test]
+    async fn does_not_share_responses_with_cookies_with_coalesced_renders() {
+        let cache = cache(CacheConfig::default());
+        let flight = match cache.lookup(&cache.key("/a".as_bytes())) {
+            Lookup::Miss(flight) => flight,
+            _ => panic!("Response is cached"),
+        };
+        let pending = match cache.lookup(&cache.key("/a".as_bytes())) {
+            Lookup::Pending(pending) => pending,
+            _ => panic!("Render is not coalesced"),
+        };
+        let res = with_header(header::SET_COOKIE, "session=user1");
+        flight.complete("/a".to_string(), Ok(res)).unwrap();
+        assert!(matches!(pending.wait().await, Coalesced::Private));
+        assert!(!is_cached(&cache, "/a"));
+    }
+}
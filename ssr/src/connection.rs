@@ -0,0 +1,286 @@
+// Connections to a js worker are long-lived and multiplexed: each rendering request is an exchange
+// of frames tagged with the request id, so concurrent renders share a few sockets instead of
+// opening a new one per render.
+//
+// The socket is read continuously, so a slow consumer of one response never stalls other exchanges
+// of the connection. Instead, frames of a response which are not consumed yet are accounted, and
+// once they exceed `MAX_BACKLOG`, the exchange fails and the worker is asked to cancel rendering,
+// so the response isn't buffered in memory as a whole.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::{
+    io::{self as aio, AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+use uuid::Uuid;
+
+use crate::{
+    error::RenderingError,
+    pool::Lease,
+    protocol::{self, Frame, OutgoingFrame},
+};
+
+// Frames waiting to be written to the socket.
+const OUTGOING_FRAMES: usize = 32;
+
+// Bytes of a single response read from the socket but not consumed yet.
+const MAX_BACKLOG: usize = 4 * 1024 * 1024;
+
+// In-flight exchanges by request id. `None` once the connection is closed.
+type Exchanges = Mutex<Option<HashMap<Uuid, Pending>>>;
+
+struct Pending {
+    incoming: mpsc::UnboundedSender<Frame>,
+    backlog: Arc<Backlog>,
+    // Rendering is in flight until the worker completes or cancels it, even if the response is
+    // not consumed yet.
+    _lease: Option<Lease>,
+}
+
+// Frames delivered to an exchange but not consumed yet.
+#[derive(Default)]
+struct Backlog {
+    bytes: AtomicUsize,
+    exceeded: AtomicBool,
+}
+
+pub(crate) struct Connection {
+    outgoing: mpsc::Sender<OutgoingFrame>,
+    // Cancellations are sent from synchronous contexts, e.g. when an exchange is dropped.
+    cancels: mpsc::UnboundedSender<OutgoingFrame>,
+    exchanges: Arc<Exchanges>,
+}
+
+impl Connection {
+    pub fn new<S>(stream: S, worker: String) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = aio::split(stream);
+        let (outgoing, outgoing_rx) = mpsc::channel(OUTGOING_FRAMES);
+        let (cancels, cancels_rx) = mpsc::unbounded_channel();
+        let exchanges = Arc::new(Mutex::new(Some(HashMap::new())));
+
+        tokio::spawn(Self::write_frames(
+            writer,
+            outgoing_rx,
+            cancels_rx,
+            exchanges.clone(),
+            worker.clone(),
+        ));
+        tokio::spawn(Self::read_frames(
+            reader,
+            exchanges.clone(),
+            cancels.clone(),
+            worker,
+        ));
+
+        Self {
+            outgoing,
+            cancels,
+            exchanges,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.exchanges
+            .lock()
+            .expect("Exchanges lock is poisoned")
+            .is_none()
+    }
+
+    // Sends a rendering request or a ping to the worker. Frames of the response are delivered to
+    // the returned exchange. The lease, if any, is released once the worker is done with the
+    // request.
+    pub async fn start(
+        self: &Arc<Self>,
+        request_id: Uuid,
+        frame: OutgoingFrame,
+        lease: Option<Lease>,
+    ) -> Result<Exchange, RenderingError> {
+        let (sender, incoming) = mpsc::unbounded_channel();
+        let backlog = Arc::new(Backlog::default());
+        let pending = Pending {
+            incoming: sender,
+            backlog: backlog.clone(),
+            _lease: lease,
+        };
+        match &mut *self.exchanges.lock().expect("Exchanges lock is poisoned") {
+            Some(exchanges) => {
+                exchanges.insert(request_id, pending);
+            }
+            None => return Err(RenderingError::RenderRequestError(closed())),
+        }
+        let exchange = Exchange {
+            request_id,
+            incoming,
+            backlog,
+            connection: self.clone(),
+            done: false,
+        };
+        if self.outgoing.clone().send(frame).await.is_err() {
+            return Err(RenderingError::RenderRequestError(closed()));
+        }
+        Ok(exchange)
+    }
+
+    fn cancel(&self, request_id: &Uuid) {
+        let in_flight = match &mut *self.exchanges.lock().expect("Exchanges lock is poisoned") {
+            Some(exchanges) => exchanges.remove(request_id).is_some(),
+            None => false,
+        };
+        if in_flight {
+            Self::send_cancel(&self.cancels, request_id);
+        }
+    }
+
+    fn send_cancel(cancels: &mpsc::UnboundedSender<OutgoingFrame>, request_id: &Uuid) {
+        if let Ok(frame) = protocol::encode_frame(protocol::FRAME_CANCEL, request_id, &[]) {
+            cancels.send(frame).ok();
+        }
+    }
+
+    // Pending exchanges get notified about the closed connection once their senders are dropped.
+    fn close(exchanges: &Exchanges) {
+        exchanges.lock().expect("Exchanges lock is poisoned").take();
+    }
+
+    async fn write_frames<W: AsyncWrite + Unpin>(
+        mut writer: W,
+        mut outgoing: mpsc::Receiver<OutgoingFrame>,
+        mut cancels: mpsc::UnboundedReceiver<OutgoingFrame>,
+        exchanges: Arc<Exchanges>,
+        worker: String,
+    ) {
+        loop {
+            // Writing stops once the connection is dropped, pending cancellations are moot by then
+            let frame = tokio::select! {
+                frame = outgoing.recv() => match frame {
+                    Some(frame) => frame,
+                    None => return,
+                },
+                Some(frame) = cancels.recv() => frame,
+            };
+            if let Err(err) = frame.write(&mut writer).await {
+                warn!(
+                    "{worker}: Failed to write to connection to the js worker: {err}",
+                    worker = worker,
+                    err = err
+                );
+                Self::close(&exchanges);
+                return;
+            }
+        }
+    }
+
+    async fn read_frames<R: AsyncRead + Unpin>(
+        mut reader: R,
+        exchanges: Arc<Exchanges>,
+        cancels: mpsc::UnboundedSender<OutgoingFrame>,
+        worker: String,
+    ) {
+        loop {
+            let (request_id, frame) = match protocol::read_frame(&mut reader).await {
+                Ok(frame) => frame,
+                Err(err) => {
+                    trace!(
+                        "{worker}: Connection to the js worker is closed: {err}",
+                        worker = worker,
+                        err = err
+                    );
+                    Self::close(&exchanges);
+                    return;
+                }
+            };
+            let last = match frame {
+                Frame::End | Frame::Error(_) | Frame::Pong => true,
+                Frame::Head(_) | Frame::Chunk(_) => false,
+            };
+            let size = frame.size();
+
+            let mut guard = exchanges.lock().expect("Exchanges lock is poisoned");
+            let in_flight = match &mut *guard {
+                Some(exchanges) => exchanges,
+                None => return,
+            };
+            // Frames of cancelled exchanges which are still in transit are discarded
+            let pending = match in_flight.get(&request_id) {
+                Some(pending) => pending,
+                None => continue,
+            };
+            let backlog = pending.backlog.bytes.fetch_add(size, Ordering::SeqCst) + size;
+            if backlog > MAX_BACKLOG {
+                warn!(
+                    "{worker}: Response to request {request_id} is not consumed, cancelling it",
+                    worker = worker,
+                    request_id = request_id
+                );
+                pending.backlog.exceeded.store(true, Ordering::SeqCst);
+                in_flight.remove(&request_id);
+                Self::send_cancel(&cancels, &request_id);
+                continue;
+            }
+            let delivered = pending.incoming.send(frame).is_ok();
+            if last || !delivered {
+                in_flight.remove(&request_id);
+            }
+        }
+    }
+}
+
+// Frames of a single rendering response. If the exchange is dropped before the response is
+// complete, the worker is asked to cancel rendering.
+pub(crate) struct Exchange {
+    request_id: Uuid,
+    incoming: mpsc::UnboundedReceiver<Frame>,
+    backlog: Arc<Backlog>,
+    connection: Arc<Connection>,
+    done: bool,
+}
+
+impl Exchange {
+    pub async fn next_frame(&mut self) -> Result<Frame, RenderingError> {
+        match self.incoming.recv().await {
+            Some(frame) => {
+                self.backlog.bytes.fetch_sub(frame.size(), Ordering::SeqCst);
+                if let Frame::End | Frame::Error(_) | Frame::Pong = frame {
+                    self.done = true;
+                }
+                Ok(frame)
+            }
+            None => {
+                self.done = true;
+                if self.backlog.exceeded.load(Ordering::SeqCst) {
+                    return Err(RenderingError::BacklogExceeded(MAX_BACKLOG));
+                }
+                Err(RenderingError::RenderResponseError(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection to the js worker is closed",
+                )))
+            }
+        }
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        if !self.done {
+            self.connection.cancel(&self.request_id);
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotConnected,
+        "connection to the js worker is closed",
+    )
+}
@@ -1,11 +1,77 @@
-use std::{fmt, io, net::AddrParseError};
+use std::{error, fmt, io, net::AddrParseError, process::ExitStatus, time::Duration};
 
+use serde_json::{Map, Value};
+
+/// An error returned by [`Ssr::new`](crate::Ssr::new) or reported when a worker fails to restart.
 #[derive(Debug)]
 pub enum InitializationError {
+    /// Address of a worker is not valid.
     InvalidAddr(AddrParseError),
+    /// [`SsrConfig::js_worker`](crate::SsrConfig::js_worker) doesn't point to an existing file.
     InvalidJsWorkerPath(io::Error),
+    /// [`SsrConfig::global_js_renderer`](crate::SsrConfig::global_js_renderer) doesn't point to
+    /// an existing file.
     InvalidGlobalJsRendererPath(io::Error),
+    /// [`Fallback::shell`](crate::Fallback::shell) couldn't be read.
+    InvalidFallbackShellPath(io::Error),
+    /// [`HtmlTemplate`](crate::HtmlTemplate) couldn't be read.
+    InvalidHtmlTemplatePath(io::Error),
+    /// [`HtmlTemplate`](crate::HtmlTemplate) is missing a required slot.
+    InvalidHtmlTemplate(String),
+    /// [`AssetManifest::path`](crate::AssetManifest::path) couldn't be read.
+    InvalidAssetManifestPath(io::Error),
+    /// [`AssetManifest::path`](crate::AssetManifest::path) is not a valid manifest.
+    InvalidAssetManifest(String),
+    /// Node.js process couldn't be spawned.
     SpawnNodeProcessError(io::Error),
+    /// [`SsrConfig::workers`](crate::SsrConfig::workers) is `0`.
+    InvalidPoolSize(usize),
+    /// [`SsrConfig::connections_per_worker`](crate::SsrConfig::connections_per_worker) is `0`.
+    InvalidConnectionsPerWorker(usize),
+    /// [`ConcurrencyLimit::max_in_flight`](crate::ConcurrencyLimit::max_in_flight) is `0`.
+    InvalidMaxInFlight(usize),
+    /// Ports of workers don't fit into the range of valid ports.
+    InvalidPortRange {
+        /// A port of the first worker.
+        port: u16,
+        /// A number of workers in the pool.
+        size: usize,
+    },
+    /// Worker didn't report readiness within
+    /// [`SsrConfig::startup_timeout`](crate::SsrConfig::startup_timeout).
+    StartupTimeout(Duration),
+    /// Worker reported readiness in an unexpected format, which usually means that versions of
+    /// the crate and the js worker don't match.
+    InvalidHandshake(String),
+    /// Worker process exited before reporting readiness, e.g. because JS renderer threw on
+    /// require or the port is already in use. Contains exit status of the process, if it could
+    /// be retrieved.
+    WorkerExitedDuringStartup(Option<ExitStatus>),
+}
+
+impl InitializationError {
+    /// Returns `true` if the error might be caused by a transient condition, such as a busy port
+    /// or an overloaded machine, so retrying might succeed. Configuration errors are permanent.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::SpawnNodeProcessError(_)
+            | Self::StartupTimeout(_)
+            | Self::WorkerExitedDuringStartup(_) => true,
+            Self::InvalidAddr(_)
+            | Self::InvalidJsWorkerPath(_)
+            | Self::InvalidGlobalJsRendererPath(_)
+            | Self::InvalidFallbackShellPath(_)
+            | Self::InvalidHtmlTemplatePath(_)
+            | Self::InvalidHtmlTemplate(_)
+            | Self::InvalidAssetManifestPath(_)
+            | Self::InvalidAssetManifest(_)
+            | Self::InvalidPoolSize(_)
+            | Self::InvalidConnectionsPerWorker(_)
+            | Self::InvalidMaxInFlight(_)
+            | Self::InvalidPortRange { .. }
+            | Self::InvalidHandshake(_) => false,
+        }
+    }
 }
 
 impl From<AddrParseError> for InitializationError {
@@ -34,24 +100,179 @@ impl fmt::Display for InitializationError {
                 "Invalid global js renderer path: {}. Make sure file at path exists and path is valid.",
                 err
             ),
+            Self::InvalidFallbackShellPath(err) => write!(
+                f,
+                "Invalid fallback shell path: {}. Make sure file at path exists and is readable.",
+                err
+            ),
+            Self::InvalidHtmlTemplatePath(err) => write!(
+                f,
+                "Invalid HTML template path: {}. Make sure file at path exists and is readable.",
+                err
+            ),
+            Self::InvalidHtmlTemplate(reason) => write!(f, "Invalid HTML template: {}", reason),
+            Self::InvalidAssetManifestPath(err) => write!(
+                f,
+                "Invalid asset manifest path: {}. Make sure file at path exists and is readable.",
+                err
+            ),
+            Self::InvalidAssetManifest(reason) => write!(f, "Invalid asset manifest: {}", reason),
             Self::SpawnNodeProcessError(err) => {
                 write!(f, "Failed to spawn worker process: {}", err)
             }
+            Self::InvalidPoolSize(size) => write!(
+                f,
+                "Invalid workers pool size: {}. At least one worker is required.",
+                size
+            ),
+            Self::InvalidConnectionsPerWorker(connections) => write!(
+                f,
+                "Invalid number of connections per worker: {}. At least one connection is required.",
+                connections
+            ),
+            Self::InvalidMaxInFlight(max) => write!(
+                f,
+                "Invalid max number of in-flight renders per worker: {}. At least one render is required.",
+                max
+            ),
+            Self::InvalidPortRange { port, size } => write!(
+                f,
+                "Invalid port range: {} workers can't be assigned consecutive ports starting from {}.",
+                size, port
+            ),
+            Self::StartupTimeout(timeout) => write!(
+                f,
+                "Worker didn't report readiness within {}ms",
+                timeout.as_millis()
+            ),
+            Self::InvalidHandshake(handshake) => write!(
+                f,
+                "Worker reported readiness with invalid handshake: {}. Make sure versions of the crate and js worker match.",
+                handshake
+            ),
+            Self::WorkerExitedDuringStartup(Some(status)) => {
+                write!(f, "Worker exited during startup with {}", status)
+            }
+            Self::WorkerExitedDuringStartup(None) => write!(f, "Worker exited during startup"),
         }
     }
 }
 
+impl error::Error for InitializationError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::InvalidAddr(err) => Some(err),
+            Self::InvalidJsWorkerPath(err)
+            | Self::InvalidGlobalJsRendererPath(err)
+            | Self::InvalidFallbackShellPath(err)
+            | Self::InvalidHtmlTemplatePath(err)
+            | Self::InvalidAssetManifestPath(err)
+            | Self::SpawnNodeProcessError(err) => Some(err),
+            Self::InvalidHtmlTemplate(_)
+            | Self::InvalidAssetManifest(_)
+            | Self::InvalidPoolSize(_)
+            | Self::InvalidConnectionsPerWorker(_)
+            | Self::InvalidMaxInFlight(_)
+            | Self::InvalidPortRange { .. }
+            | Self::StartupTimeout(_)
+            | Self::InvalidHandshake(_)
+            | Self::WorkerExitedDuringStartup(_) => None,
+        }
+    }
+}
+
+/// An error returned by rendering functions of [`Ssr`](crate::Ssr).
 #[derive(Debug)]
 pub enum RenderingError {
+    /// Worker is not running, e.g. it crashed and is being restarted.
     WorkerIsUnavailable,
+    /// Connection to the worker couldn't be established.
     ConnectionError(io::Error),
+    /// Uri of the request has no path.
     InvalidUri,
+    /// [`JsRenderer::Global`](crate::JsRenderer::Global) is requested, but
+    /// [`SsrConfig::global_js_renderer`](crate::SsrConfig::global_js_renderer) is not provided.
     GlobalRendererNotProvided,
+    /// Meta of the request couldn't be serialized.
     UrlSerializationError(serde_json::Error),
+    /// Data passed to the renderer couldn't be serialized.
     DataSerializationError(serde_json::Error),
+    /// Rendering request couldn't be sent to the worker.
     RenderRequestError(io::Error),
+    /// Response couldn't be received from the worker, e.g. because the worker crashed.
     RenderResponseError(io::Error),
-    JsExceptionDuringRendering(String),
+    /// JS renderer threw an exception or the js worker failed to handle the request. See
+    /// [`JsError::origin`](JsError::origin) to tell these cases apart.
+    JsExceptionDuringRendering(Box<JsError>),
+    /// Worker responded with something that is not a valid response, e.g. an invalid status
+    /// code or header returned by JS renderer.
+    InvalidResponse(String),
+    /// Rendering didn't complete within the render timeout or the deadline of the request.
+    Timeout(Duration),
+    /// [`Ssr::shutdown`](crate::Ssr::shutdown) was called.
+    ShuttingDown,
+    /// All workers are busy and either the queue of pending renders is full or the render waited
+    /// in the queue longer than
+    /// [`ConcurrencyLimit::queue_timeout`](crate::ConcurrencyLimit::queue_timeout). The render
+    /// wasn't sent to a worker, so the page can be rendered on the client instead.
+    Overloaded,
+    /// Most of recent renders of the worker failed, so its circuit breaker is open and renders
+    /// are not sent to it for a while. See
+    /// [`SsrConfig::circuit_breaker`](crate::SsrConfig::circuit_breaker).
+    CircuitOpen,
+    /// Body of a streamed response wasn't consumed while the worker kept rendering it, so more
+    /// than the given number of bytes piled up in memory. Rendering is cancelled, so it doesn't
+    /// hold up the worker.
+    BacklogExceeded(usize),
+}
+
+impl RenderingError {
+    /// Returns `true` if the error is caused by a transient condition, such as a crashed worker,
+    /// a broken connection or a timeout, so retrying the request might succeed. Errors caused by
+    /// the request itself or by JS renderer are permanent.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::WorkerIsUnavailable
+            | Self::ConnectionError(_)
+            | Self::RenderRequestError(_)
+            | Self::RenderResponseError(_)
+            | Self::Timeout(_)
+            | Self::Overloaded
+            | Self::CircuitOpen => true,
+            Self::InvalidUri
+            | Self::GlobalRendererNotProvided
+            | Self::UrlSerializationError(_)
+            | Self::DataSerializationError(_)
+            | Self::JsExceptionDuringRendering(_)
+            | Self::InvalidResponse(_)
+            | Self::ShuttingDown
+            | Self::BacklogExceeded(_) => false,
+        }
+    }
+
+    // Errors can't be cloned because of underlying I/O and serialization errors, so renders
+    // coalesced with a failed one get a copy which keeps the kind and the message of the source.
+    pub(crate) fn replicate(&self) -> Self {
+        let io = |err: &io::Error| io::Error::new(err.kind(), err.to_string());
+        let json = |err: &serde_json::Error| serde::ser::Error::custom(err);
+        match self {
+            Self::WorkerIsUnavailable => Self::WorkerIsUnavailable,
+            Self::ConnectionError(err) => Self::ConnectionError(io(err)),
+            Self::InvalidUri => Self::InvalidUri,
+            Self::GlobalRendererNotProvided => Self::GlobalRendererNotProvided,
+            Self::UrlSerializationError(err) => Self::UrlSerializationError(json(err)),
+            Self::DataSerializationError(err) => Self::DataSerializationError(json(err)),
+            Self::RenderRequestError(err) => Self::RenderRequestError(io(err)),
+            Self::RenderResponseError(err) => Self::RenderResponseError(io(err)),
+            Self::JsExceptionDuringRendering(err) => Self::JsExceptionDuringRendering(err.clone()),
+            Self::InvalidResponse(reason) => Self::InvalidResponse(reason.clone()),
+            Self::Timeout(timeout) => Self::Timeout(*timeout),
+            Self::ShuttingDown => Self::ShuttingDown,
+            Self::Overloaded => Self::Overloaded,
+            Self::CircuitOpen => Self::CircuitOpen,
+            Self::BacklogExceeded(max) => Self::BacklogExceeded(*max),
+        }
+    }
 }
 
 impl fmt::Display for RenderingError {
@@ -69,6 +290,117 @@ impl fmt::Display for RenderingError {
             Self::JsExceptionDuringRendering(err) => {
                 write!(f, "JS Exception during rendering: {}", err)
             }
+            Self::InvalidResponse(reason) => {
+                write!(f, "Invalid response of js renderer: {}", reason)
+            }
+            Self::Timeout(timeout) => {
+                write!(f, "Rendering timed out after {}ms", timeout.as_millis())
+            }
+            Self::ShuttingDown => write!(f, "Renderer is shutting down"),
+            Self::Overloaded => write!(f, "Renderer is overloaded"),
+            Self::CircuitOpen => write!(f, "Circuit breaker of the worker is open"),
+            Self::BacklogExceeded(max) => write!(
+                f,
+                "Streamed body is not consumed, more than {} bytes are pending",
+                max
+            ),
+        }
+    }
+}
+
+impl error::Error for RenderingError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::ConnectionError(err)
+            | Self::RenderRequestError(err)
+            | Self::RenderResponseError(err) => Some(err),
+            Self::UrlSerializationError(err) | Self::DataSerializationError(err) => Some(err),
+            Self::JsExceptionDuringRendering(err) => Some(err.as_ref()),
+            Self::WorkerIsUnavailable
+            | Self::InvalidUri
+            | Self::GlobalRendererNotProvided
+            | Self::InvalidResponse(_)
+            | Self::Timeout(_)
+            | Self::ShuttingDown
+            | Self::Overloaded
+            | Self::CircuitOpen
+            | Self::BacklogExceeded(_) => None,
+        }
+    }
+}
+
+/// An exception thrown during rendering, as reported by the js worker.
+#[derive(Clone, Debug)]
+pub struct JsError {
+    /// Name of the error, e.g. `TypeError`.
+    pub name: String,
+    /// Message of the error.
+    pub message: String,
+    /// Raw stack of the error, if any.
+    pub stack: Option<String>,
+    /// Parsed frames of the stack, from the innermost one.
+    pub frames: Vec<JsStackFrame>,
+    /// An error set as `cause` of this error.
+    pub cause: Option<Box<JsError>>,
+    /// Custom properties of the error, e.g. `statusCode` or `code`. Values that can't be
+    /// represented in JSON are converted to strings.
+    pub properties: Map<String, Value>,
+    /// Defines whether the error was thrown by JS renderer or by the js worker itself.
+    pub origin: JsErrorOrigin,
+}
+
+/// A frame of a stack of [`JsError`](JsError).
+#[derive(Clone, Debug)]
+pub struct JsStackFrame {
+    /// Name of the function, if any.
+    pub function: Option<String>,
+    /// Path or url of the file.
+    pub file: Option<String>,
+    /// 1-based line number.
+    pub line: Option<u32>,
+    /// 1-based column number.
+    pub column: Option<u32>,
+}
+
+/// Defines where [`JsError`](JsError) was thrown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JsErrorOrigin {
+    /// JS renderer threw while rendering a response or streaming its body.
+    Renderer,
+    /// The js worker failed to handle the request, e.g. JS renderer is missing or returned
+    /// a response of an unexpected shape.
+    Worker,
+}
+
+impl fmt::Display for JsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.stack {
+            Some(stack) => write!(f, "{}", stack),
+            None => write!(f, "{}: {}", self.name, self.message),
+        }
+    }
+}
+
+impl error::Error for JsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.cause {
+            Some(cause) => Some(cause.as_ref()),
+            None => None,
+        }
+    }
+}
+
+impl fmt::Display for JsStackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = self.file.as_deref().unwrap_or("<anonymous>");
+        let location = match (self.line, self.column) {
+            (Some(line), Some(column)) => format!("{}:{}:{}", file, line, column),
+            (Some(line), None) => format!("{}:{}", file, line),
+            _ => file.to_string(),
+        };
+        match &self.function {
+            Some(function) => write!(f, "{} ({})", function, location),
+            None => write!(f, "{}", location),
         }
     }
 }
@@ -0,0 +1,132 @@
+use std::{fs, path::PathBuf};
+
+use http::{
+    header::{self, HeaderMap, HeaderValue},
+    StatusCode,
+};
+use serde::Serialize;
+
+use crate::{
+    error::{InitializationError, RenderingError},
+    response::RenderResponse,
+    template::{HtmlTemplate, TemplateSlots},
+};
+
+/// A static HTML shell of the app served instead of a rendered page when rendering fails, so
+/// the app gets rendered on the client instead. See
+/// [`SsrConfig::fallback`](crate::SsrConfig::fallback).
+///
+/// The shell is an [`HtmlTemplate`](crate::HtmlTemplate) rendered with empty head and body, so
+/// it has the same slots: a hydration script with data of the request and a CSP nonce passed via
+/// [`RenderOptions::nonce`](crate::RenderOptions::nonce) are injected into it. Unlike a page
+/// template, the shell doesn't need to have a body slot.
+///
+/// # Example
+///
+/// ```html
+/// <!DOCTYPE html>
+/// <html>
+///   <body>
+///     <div id="app"></div>
+///     <!--ssr-hydration-->
+///     <script nonce="%SSR_NONCE%" src="/app.js"></script>
+///   </body>
+/// </html>
+/// ```
+#[derive(Clone, Debug)]
+pub struct Fallback {
+    /// Path to the shell, e.g. `index.html` of a SPA. It's read once on startup.
+    pub shell: PathBuf,
+    /// Slots of the shell.
+    pub slots: TemplateSlots,
+}
+
+impl Fallback {
+    /// Creates a fallback with the given shell and the default slots.
+    pub fn new(shell: impl Into<PathBuf>) -> Self {
+        Self {
+            shell: shell.into(),
+            slots: TemplateSlots::default(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Shell(HtmlTemplate);
+
+impl Shell {
+    pub fn load(cfg: Fallback) -> Result<Self, InitializationError> {
+        match fs::read_to_string(&cfg.shell) {
+            Ok(html) => Ok(Self(HtmlTemplate::parse(&html, &cfg.slots))),
+            Err(err) => Err(InitializationError::InvalidFallbackShellPath(err)),
+        }
+    }
+
+    // Errors caused by the request itself are bugs rather than failures to render, so they are
+    // not hidden behind the shell.
+    pub fn applies_to(err: &RenderingError) -> bool {
+        !matches!(
+            err,
+            RenderingError::InvalidUri
+                | RenderingError::GlobalRendererNotProvided
+                | RenderingError::UrlSerializationError(_)
+                | RenderingError::DataSerializationError(_)
+        )
+    }
+
+    // The shell is not supposed to be stored by the cache or proxies, so the page gets rendered
+    // on the server again once it's possible.
+    pub fn render<D: Serialize>(
+        &self,
+        data: &D,
+        nonce: Option<&str>,
+    ) -> Result<RenderResponse, RenderingError> {
+        let mut res = RenderResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            head: String::new(),
+            body: String::new(),
+        };
+        res.body = self.0.render(&res, data, nonce)?;
+        res.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        res.headers
+            .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell(html: &str) -> Shell {
+        Shell(HtmlTemplate::parse(html, &TemplateSlots::default()))
+    }
+
+    #[test]
+    fn injects_data_and_nonce_into_slots() {
+        let shell = shell(
+            "<div id=\"app\"></div><!--ssr-hydration--><script nonce=\"%SSR_NONCE%\"></script>",
+        );
+        let res = shell.render(&["</script>"], Some("abc")).unwrap();
+        assert_eq!(
+            res.body,
+            "<div id=\"app\"></div>\
+             <script nonce=\"abc\">window.__SSR_DATA__ = [\"\\u003c/script\\u003e\"];</script>\
+             <script nonce=\"abc\"></script>"
+        );
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.headers[header::CACHE_CONTROL], "no-store");
+    }
+
+    #[test]
+    fn leaves_head_and_body_slots_empty() {
+        let shell = shell("<head><!--ssr-head--></head><div><!--ssr-body--></div>");
+        let res = shell.render(&(), None).unwrap();
+        assert_eq!(res.body, "<head></head><div></div>");
+        assert_eq!(res.head, "");
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+/// Health of the workers pool, returned by [`ssr.health`](crate::Ssr::health).
+#[derive(Clone, Debug)]
+pub struct Health {
+    /// Whether [`Ssr::shutdown`](crate::Ssr::shutdown) was called.
+    pub shutting_down: bool,
+    /// Health of each worker in the pool, in the order of their indexes.
+    pub workers: Vec<WorkerHealth>,
+}
+
+impl Health {
+    /// Returns `true` if rendering requests can be handled, i.e. the pool is not shutting down and
+    /// at least one worker is [`Ready`](WorkerState::Ready). Useful as a readiness probe.
+    pub fn is_ready(&self) -> bool {
+        !self.shutting_down
+            && self
+                .workers
+                .iter()
+                .any(|worker| worker.state == WorkerState::Ready)
+    }
+}
+
+/// Health of a single worker.
+#[derive(Clone, Debug)]
+pub struct WorkerHealth {
+    /// State of the worker.
+    pub state: WorkerState,
+    /// Pid of the running process, if there is one.
+    pub pid: Option<u32>,
+    /// Total number of restarts of the worker.
+    pub restarts: usize,
+    /// How long the current process has been running.
+    pub uptime: Option<Duration>,
+    /// A number of renders in flight.
+    pub in_flight: usize,
+    /// Round-trip time of a ping, if the worker responded to it.
+    pub latency: Option<Duration>,
+}
+
+/// State of a worker, as reported by [`ssr.health`](crate::Ssr::health).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkerState {
+    /// Process is being restarted after it exited.
+    Starting,
+    /// Process responds to pings and accepts rendering requests.
+    Ready,
+    /// Process is running, but it doesn't respond to pings in time or its circuit breaker is
+    /// open, so rendering requests are likely to fail.
+    Degraded,
+    /// Process exited and won't be restarted, either because of
+    /// [`RestartPolicy::Never`](crate::RestartPolicy::Never) or because the pool is shut down.
+    Dead,
+}
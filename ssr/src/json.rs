@@ -1,56 +1,189 @@
-// This is slightly modified version of formatter taken from
+//! JSON serialization which is safe to inline into HTML, e.g. to pass data for hydration from
+//! the server to the client.
+//!
+//! A plain JSON string can terminate a `<script>` element it's inlined into (`</script>`), open
+//! an HTML comment (`<!--`) which changes how the rest of the script is parsed, or contain
+//! U+2028 and U+2029 characters, which are line terminators for older JS parsers. Serializers of
+//! this module escape such sequences in both values and object keys, so the output stays valid
+//! JSON with the same value.
+//!
+//! Data passed to JS renderer is serialized with [`Escaping::ScriptTag`](Escaping::ScriptTag)
+//! already, and available as `hydrationData`.
+//!
+//! # Example
+//!
+//! ```rust
+//! let data = ssr::json::to_script_safe_string(&["</script>"]).unwrap();
+//! assert_eq!(data, r#"["\u003c/script\u003e"]"#);
+//! let html = format!("<script>window.__DATA__ = {};</script>", data);
+//! ```
+
+// Escaping of string fragments is a modified version of the formatter taken from
 // https://github.com/xd009642/tarpaulin/blob/fd7059131cf7f838a68e681a9ddeefb26a8adf7c/src/report/safe_json.rs
 // All copyrights belong to the author of this implementation.
 
-use std::{default::Default, io};
+use std::io;
 
+use serde::Serialize;
 use serde_json::{
     ser::{CharEscape, CompactFormatter, Formatter},
     Serializer,
 };
 
-struct JsonFormatter(CompactFormatter);
+/// Escaping profile, which depends on where in an HTML document the JSON is placed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Escaping {
+    /// JSON is placed into a `<script>` element, either as a JS expression or as a content of
+    /// `<script type="application/json">`. `<`, `>`, `&`, U+2028 and U+2029 are escaped in
+    /// strings, e.g. `<` becomes `\u003c`.
+    ScriptTag,
+    /// JSON is placed into a value of an HTML attribute, quoted with either double or single
+    /// quotes. On top of [`ScriptTag`](Escaping::ScriptTag) escaping, `'` is escaped in strings,
+    /// and quotes of strings become `&quot;`, so the output is JSON once the attribute is parsed
+    /// by a browser, e.g. `JSON.parse(element.dataset.props)`.
+    HtmlAttribute,
+    /// JSON is not placed into HTML, so nothing is escaped on top of what JSON requires.
+    PlainJson,
+}
 
-impl Default for JsonFormatter {
-    fn default() -> Self {
-        JsonFormatter(CompactFormatter)
+impl Escaping {
+    fn escape(self, char: char) -> Option<&'static [u8]> {
+        let escaped: &[u8] = match (self, char) {
+            (Escaping::PlainJson, _) => return None,
+            (_, '<') => b"\\u003c",
+            (_, '>') => b"\\u003e",
+            (_, '&') => b"\\u0026",
+            (_, '\u{2028}') => b"\\u2028",
+            (_, '\u{2029}') => b"\\u2029",
+            (Escaping::HtmlAttribute, '\'') => b"\\u0027",
+            _ => return None,
+        };
+        Some(escaped)
+    }
+
+    fn quote(self) -> &'static [u8] {
+        match self {
+            Escaping::HtmlAttribute => b"&quot;",
+            Escaping::ScriptTag | Escaping::PlainJson => b"\"",
+        }
     }
 }
 
-impl Formatter for JsonFormatter {
-    fn write_string_fragment<W: ?Sized>(&mut self, writer: &mut W, fragment: &str) -> io::Result<()>
+struct JsonFormatter {
+    escaping: Escaping,
+}
+
+impl JsonFormatter {
+    fn write_escaped<W>(&self, writer: &mut W, fragment: &str) -> io::Result<()>
     where
-        W: io::Write,
+        W: ?Sized + io::Write,
     {
         let mut start = 0;
-        let mut code_length = 0;
-        for char in fragment.chars() {
-            code_length += char.len_utf8();
-            let escape = match char {
-                '<' | '>' | '&' => CharEscape::AsciiControl(char as u8),
-                _ => continue,
+        for (idx, char) in fragment.char_indices() {
+            let escaped = match self.escaping.escape(char) {
+                Some(escaped) => escaped,
+                None => continue,
             };
-            if start < code_length - 1 {
-                self.0
-                    .write_string_fragment(writer, &fragment[start..code_length - 1])?;
+            if start < idx {
+                writer.write_all(&fragment.as_bytes()[start..idx])?;
             }
+            writer.write_all(escaped)?;
+            start = idx + char.len_utf8();
+        }
+        if start < fragment.len() {
+            writer.write_all(&fragment.as_bytes()[start..])?;
+        }
+        Ok(())
+    }
+}
 
-            self.write_char_escape(writer, escape)?;
+impl Formatter for JsonFormatter {
+    fn begin_string<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(self.escaping.quote())
+    }
 
-            start = code_length;
-        }
+    fn end_string<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(self.escaping.quote())
+    }
+
+    fn write_string_fragment<W>(&mut self, writer: &mut W, fragment: &str) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.write_escaped(writer, fragment)
+    }
 
-        if start < code_length {
-            self.0.write_string_fragment(writer, &fragment[start..])?;
+    fn write_char_escape<W>(&mut self, writer: &mut W, escape: CharEscape) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        match (self.escaping, escape) {
+            (Escaping::HtmlAttribute, CharEscape::Quote) => writer.write_all(b"\\u0022"),
+            (_, escape) => CompactFormatter.write_char_escape(writer, escape),
         }
+    }
 
-        Ok(())
+    // Raw JSON (e.g. `serde_json::value::RawValue`) bypasses escaping of strings. Escaped
+    // characters can't appear in JSON outside of strings, except for quotes of strings.
+    fn write_raw_fragment<W>(&mut self, writer: &mut W, fragment: &str) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        match self.escaping {
+            Escaping::HtmlAttribute => {
+                for (idx, part) in fragment.split('"').enumerate() {
+                    if idx > 0 {
+                        writer.write_all(self.escaping.quote())?;
+                    }
+                    self.write_escaped(writer, part)?;
+                }
+                Ok(())
+            }
+            Escaping::ScriptTag | Escaping::PlainJson => self.write_escaped(writer, fragment),
+        }
     }
 }
 
-pub fn to_vec<T: serde::Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, serde_json::Error> {
+/// Serializes a value to a JSON string which is safe to inline into a `<script>` element. Same
+/// as [`to_string`](to_string) with [`Escaping::ScriptTag`](Escaping::ScriptTag).
+pub fn to_script_safe_string<T>(value: &T) -> Result<String, serde_json::Error>
+where
+    T: Serialize + ?Sized,
+{
+    to_string(value, Escaping::ScriptTag)
+}
+
+/// Serializes a value to a JSON string escaped according to the profile.
+pub fn to_string<T>(value: &T, escaping: Escaping) -> Result<String, serde_json::Error>
+where
+    T: Serialize + ?Sized,
+{
+    let json = to_vec(value, escaping)?;
+    Ok(String::from_utf8(json).expect("Serialized JSON is not a valid UTF-8"))
+}
+
+/// Serializes a value to a JSON byte vector escaped according to the profile.
+pub fn to_vec<T>(value: &T, escaping: Escaping) -> Result<Vec<u8>, serde_json::Error>
+where
+    T: Serialize + ?Sized,
+{
     let mut writer = Vec::new();
-    let mut ser = Serializer::with_formatter(&mut writer, JsonFormatter::default());
-    value.serialize(&mut ser)?;
+    to_writer(&mut writer, value, escaping)?;
     Ok(writer)
 }
+
+/// Serializes a value as JSON escaped according to the profile into a writer.
+pub fn to_writer<W, T>(writer: W, value: &T, escaping: Escaping) -> Result<(), serde_json::Error>
+where
+    W: io::Write,
+    T: Serialize + ?Sized,
+{
+    let mut ser = Serializer::with_formatter(writer, JsonFormatter { escaping });
+    value.serialize(&mut ser)
+}
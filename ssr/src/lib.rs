@@ -18,15 +18,24 @@
 //! ```
 //!
 //! ## How it works
-//! On application start, you create an [`Ssr`](Ssr) instance. Under the hood, it spins up a
-//! Node.js worker ready to accept rendering requests. [`Ssr`](Ssr) instance should be stored in a
+//! On application start, you create an [`Ssr`](Ssr) instance. Under the hood, it spins up a pool
+//! of Node.js workers ready to accept rendering requests. [`Ssr`](Ssr) instance should be stored in a
 //! web server's state, so handlers can access it during a handling of incoming requests.
 //!
-//! [`Ssr`](Ssr) exposes a single method [`render`](Ssr::render), which accepts [`Uri`](http::Uri)
+//! [`Ssr`](Ssr) exposes method [`render`](Ssr::render), which accepts [`Uri`](http::Uri)
 //! and serializable data as an input. If everything went smooth, it returns a rendered `String`.
 //! This string can be a plain HTML or an app-specific encoded object with additional
 //! metadata—whatever returned from a JS renderer, supplied by the app.
 //!
+//! If JS renderer needs to tell a server which status code, headers or head tags to respond with
+//! (e.g. a route is not found or a user must be redirected), use
+//! [`render_response`](Ssr::render_response), which returns a structured
+//! [`RenderResponse`](RenderResponse).
+//!
+//! To flush the beginning of a document to a client before the whole page is rendered (e.g. with
+//! React's `renderToPipeableStream`), use [`render_stream`](Ssr::render_stream). It resolves as
+//! soon as the response meta is available and yields body chunks as they arrive from the worker.
+//!
 //! ## Initialization
 //!
 //! ```rust
@@ -34,15 +43,77 @@
 //!   Ssr::new(
 //!     SsrConfig {
 //!       port: 9000,
+//!       workers: 4,
 //!       js_worker: PathBuf::from("./node_modules/ssr-rs/worker.js"),
 //!       js_worker_log: JsWorkerLog::Verbose,
 //!       global_js_renderer: Some(PathBuf::from("./js/ssr.js")),
+//!       ..SsrConfig::default()
 //!     }
 //!   );
 //! ```
 //!
 //! ### `port`
-//! A port that Node.js worker will be listening on.
+//! A port that Node.js worker will be listening on. If the pool consists of multiple workers, each
+//! subsequent worker listens on the next port, so with `port: 9000` and `workers: 4` ports
+//! `9000..=9003` must be available. Set it to `0` to let each worker bind a free ephemeral port
+//! reported back to Rust side on startup, so there's no need to coordinate ports between services
+//! or parallel test runs on the same machine.
+//!
+//! ### `transport`
+//! By default, workers listen on TCP ports of `127.0.0.1`. On Unix, they can listen on Unix domain
+//! sockets instead, which avoids picking free ports, is not reachable by other local users and
+//! saves TCP loopback overhead:
+//!
+//! ```rust
+//! SsrConfig {
+//!   transport: Transport::UnixSocket { dir: PathBuf::from("/run/my-app") },
+//!   ..SsrConfig::default()
+//! }
+//! ```
+//!
+//! ### `workers`
+//! A number of Node.js workers in the pool. Each rendering request is dispatched to the worker
+//! with the least number of in-flight renders.
+//!
+//! ### `connections_per_worker`
+//! A number of persistent connections to each worker. Connections are established lazily and
+//! reused across rendering requests. Concurrent renders are multiplexed over them, so a couple of
+//! connections is usually enough even under high load.
+//!
+//! ### `concurrency_limit`
+//! By default, renders are sent to workers right away, so under a traffic spike Node.js event loop
+//! piles them up and latency grows for everyone. To bound the load, set a max number of renders in
+//! flight per worker. The rest wait in a bounded queue, in the order they came, and fail fast with
+//! [`RenderingError::Overloaded`](RenderingError::Overloaded) once it's full or they've waited for
+//! too long, so a handler can fall back to client-side rendering:
+//!
+//! ```rust
+//! SsrConfig {
+//!   concurrency_limit: Some(ConcurrencyLimit {
+//!     max_in_flight: 32,
+//!     max_queued: 100,
+//!     queue_timeout: Duration::from_millis(200),
+//!   }),
+//!   ..SsrConfig::default()
+//! }
+//! ```
+//!
+//! ### `circuit_breaker`
+//! If a worker fails most of renders, e.g. after a bad deploy of the renderer bundle, there's no
+//! point in paying for a round-trip to it on every request. With a circuit breaker enabled, once
+//! the share of failed renders reaches a threshold, renders dispatched to the worker fail fast with
+//! [`RenderingError::CircuitOpen`](RenderingError::CircuitOpen) (or get the [fallback](#fallback)
+//! shell) for a while, after which a single render probes whether the worker has recovered:
+//!
+//! ```rust
+//! SsrConfig {
+//!   circuit_breaker: Some(CircuitBreakerConfig {
+//!     failure_threshold: 0.8,
+//!     ..CircuitBreakerConfig::default()
+//!   }),
+//!   ..SsrConfig::default()
+//! }
+//! ```
 //!
 //! ### `js_worker`
 //! Path to Node.js worker installed from `npm`. It should be relative to the
@@ -59,14 +130,65 @@
 //! request but keep in mind that it would introduce additional runtime overhead since JS module
 //! has to be required during a request as opposed to requiring it once on application startup.
 //!
+//! ### `source_maps`
+//! If JS renderer is a minified bundle, stack frames of its errors are useless without source
+//! maps. When enabled (default), frames of [`JsError`](JsError) are mapped to original sources
+//! using a source map next to the bundle, e.g. `ssr.js.map` for `ssr.js`.
+//!
+//! ### `cache`
+//! If the same pages are rendered over and over with the same data, rendered responses can be
+//! cached in memory. The cache is disabled by default. Once enabled, responses of
+//! [`render`](Ssr::render) and [`render_response`](Ssr::render_response) are cached by path and
+//! query, JS renderer, forwarded headers and cookies, and data, with the least recently used ones
+//! evicted once the cache is full:
+//!
+//! ```rust
+//! SsrConfig {
+//!   cache: Some(CacheConfig {
+//!     ttl: Duration::from_secs(10),
+//!     ..CacheConfig::default()
+//!   }),
+//!   ..SsrConfig::default()
+//! }
+//! ```
+//!
+//! Only successful responses are cached. Responses with `Set-Cookie` header or with
+//! `Cache-Control: private` are specific to the user, so they're neither cached nor shared with
+//! other requests. A request can opt out via [`RenderOptions::cache`](RenderOptions::cache), and
+//! stale responses can be evicted via [`ssr.invalidate_cache`](Ssr::invalidate_cache) or
+//! [`ssr.clear_cache`](Ssr::clear_cache).
+//!
+//! Concurrent renders of the same request are coalesced into a single render, so an expired
+//! response of a popular page doesn't send a burst of identical requests to workers. With
+//! [`stale_while_revalidate`](CacheConfig::stale_while_revalidate), an expired response keeps
+//! being served for a while as it's being re-rendered in the background.
+//!
+//! ### `asset_manifest`
+//! An asset manifest of Vite or webpack, which maps chunks of the app to their hashed files. It's
+//! passed to JS renderer as `manifest`, and the renderer reports chunks used by the page as
+//! `chunks` of the response object. Files of these chunks and their static imports are added to
+//! the head tags as `<link rel="stylesheet">` and preloads of scripts, `<link rel="modulepreload">`
+//! for ES modules of Vite and `<link rel="preload" as="script">` for scripts of webpack, and to
+//! `Link` headers of the response:
+//!
+//! ```rust
+//! SsrConfig {
+//!   asset_manifest: Some(AssetManifest::new("./dist/manifest.json")),
+//!   ..SsrConfig::default()
+//! }
+//! ```
+//!
 //! ## Rendering
 //! In request handlers, you need to get [`Ssr`](Ssr) instance from your server's state. Once you
 //! have it (as well as all the required data to handle the current request), call
 //! [`ssr.render`](Ssr::render) function with the following input:
-//! - [`Uri`](http::Uri): uri of the current request
+//! - [`RenderRequest`](RenderRequest): the current request. It can be created from
+//! [`Uri`](http::Uri), or from [`Request`](http::Request) and [`Parts`](http::request::Parts) if
+//! JS renderer needs method, headers and cookies of the request as well.
 //! - `Data: impl Serialize`: anything that implements [`Serialize`](serde::Serialize)
 //! - [`JsRenderer`](JsRenderer): an enum that tells to use either a global JS renderer or a
-//! renderer specific to this request.
+//! renderer specific to this request. Alternatively, [`RenderOptions`](RenderOptions) can be
+//! passed to override the defaults, such as a render timeout, for this request.
 //!
 //! ```rust
 //! let uri = req.uri();
@@ -79,15 +201,112 @@
 //!     }
 //! }
 //! ```
+//!
+//! JS renderer can be async, e.g. to prefetch data before rendering. A rejected promise results in
+//! [`RenderingError::JsExceptionDuringRendering`](RenderingError::JsExceptionDuringRendering),
+//! and the render timeout applies while the promise is pending:
+//!
+//! ```js
+//! module.exports.render = async ({url, jsonData}) => {
+//!   const client = createClient();
+//!   const app = <App url={url} client={client} data={jsonData} />;
+//!   await getDataFromTree(app);
+//!   return renderToString(app);
+//! };
+//! ```
+//!
+//! ## Hydration
+//! `hydrationData` passed to JS renderer is data of the request serialized to JSON, which is
+//! safe to inline into a `<script>` element as is. To serialize other values the same way, or to
+//! place JSON into an HTML attribute, use [`json`](json) module:
+//!
+//! ```rust
+//! let props = ssr::json::to_string(&props, ssr::json::Escaping::HtmlAttribute)?;
+//! let html = format!(r#"<div data-props="{}"></div>"#, props);
+//! ```
+//!
+//! ## HTML template
+//! If JS renderer returns the markup of the app only, [`HtmlTemplate`](HtmlTemplate) assembles
+//! the document: it's loaded once, e.g. from `index.html` produced by a bundler, and head tags,
+//! the body, a hydration script with data of the request and a CSP nonce are injected into its
+//! slots on each render:
+//!
+//! ```rust
+//! let template = HtmlTemplate::load("./dist/index.html")?;
+//! let res = ssr.render_response(uri, &data, JsRenderer::Global).await?;
+//! let html = template.render(&res, &data, Some(&nonce))?;
+//! ```
+//!
+//! ## Fallback
+//! If rendering fails due to a timeout, an overload, a crashed worker or a JS exception, an error
+//! page is rarely the best response. When [`SsrConfig::fallback`](SsrConfig::fallback) is set,
+//! such failures are logged, and a static HTML shell of the app, e.g. `index.html` of a SPA, is
+//! served instead, so the app gets rendered on the client. The shell has the same slots as an
+//! [`HtmlTemplate`](HtmlTemplate): a hydration script with data of the request replaces
+//! `<!--ssr-hydration-->`, and a CSP nonce passed via [`RenderOptions::nonce`](RenderOptions::nonce)
+//! replaces `%SSR_NONCE%`:
+//!
+//! ```rust
+//! SsrConfig {
+//!   fallback: Some(Fallback::new("./dist/index.html")),
+//!   ..SsrConfig::default()
+//! }
+//! ```
+//!
+//! ## Health
+//! [`ssr.health`](Ssr::health) pings each worker with a lightweight message, which doesn't
+//! involve a render, and reports its state, pid, uptime and number of restarts. A worker which
+//! doesn't respond within [`SsrConfig::ping_timeout`](SsrConfig::ping_timeout) is reported as
+//! degraded. Use [`health.is_ready`](Health::is_ready) as a readiness probe:
+//!
+//! ```rust
+//! let health = ssr.health().await;
+//! if !health.is_ready() {
+//!     return HttpResponse::ServiceUnavailable().finish();
+//! }
+//! ```
+//!
+//! ## Shutdown
+//! Workers get killed once [`Ssr`](Ssr) instance is dropped. To let in-flight renders complete
+//! (e.g. when a server is draining connections during a deploy), call
+//! [`ssr.shutdown`](Ssr::shutdown) with a deadline after the server has stopped:
+//!
+//! ```rust
+//! server.await?;
+//! ssr.shutdown(Duration::from_secs(10)).await;
+//! ```
 
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate serde_json;
 
+mod breaker;
+mod cache;
+mod connection;
 mod error;
-mod json;
+mod fallback;
+mod health;
+pub mod json;
+mod manifest;
+mod pool;
+mod protocol;
+mod request;
+mod response;
 mod ssr;
+mod template;
 mod worker;
 
-pub use ssr::{JsRenderer, JsWorkerLog, Ssr, SsrConfig};
+pub use breaker::CircuitBreakerConfig;
+pub use cache::CacheConfig;
+pub use error::{InitializationError, JsError, JsErrorOrigin, JsStackFrame, RenderingError};
+pub use fallback::Fallback;
+pub use health::{Health, WorkerHealth, WorkerState};
+pub use manifest::AssetManifest;
+pub use request::{HeaderFilter, RenderRequest};
+pub use response::{RenderResponse, RenderStream};
+pub use ssr::{
+    ConcurrencyLimit, JsRenderer, JsWorkerLog, RenderOptions, RestartPolicy, Ssr, SsrConfig,
+    Transport, WorkerEvent, WorkerEventHook,
+};
+pub use template::{HtmlTemplate, TemplateSlots};
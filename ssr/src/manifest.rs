@@ -0,0 +1,489 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+};
+
+use http::header::{self, HeaderValue};
+use serde_json::Value;
+
+use crate::{error::InitializationError, response::Head, template};
+
+/// An asset manifest produced by a bundler, which maps chunks of the app to their hashed files.
+/// See [`SsrConfig::asset_manifest`](crate::SsrConfig::asset_manifest).
+///
+/// Both Vite `manifest.json` (`build.manifest`) and a flat manifest of webpack
+/// (`webpack-manifest-plugin`) are supported. The manifest is passed to JS renderer as
+/// `manifest`, and the renderer reports chunks used by the page as `chunks` of the response
+/// object, which are keys of the manifest:
+///
+/// ```js
+/// module.exports.render = ({url, manifest}) => ({
+///   body: renderToString(<App url={url} />),
+///   chunks: ["src/main.tsx", `src/pages/${page(url)}.tsx`],
+/// });
+/// ```
+///
+/// Files of the reported chunks, including the chunks they import and their CSS, are added to
+/// [`RenderResponse::head`](crate::RenderResponse::head) as `<link rel="stylesheet">` tags and
+/// preloads of scripts, and to `Link` headers of the response, so browsers and CDNs can start
+/// fetching them early. Scripts of a Vite manifest are ES modules, so they are preloaded via
+/// `<link rel="modulepreload">`, while the ones of a webpack manifest are classic scripts, which
+/// are preloaded via `<link rel="preload" as="script">`.
+#[derive(Clone, Debug)]
+pub struct AssetManifest {
+    /// Path to the manifest. It's read once on startup.
+    pub path: PathBuf,
+    /// A prefix of relative files of the manifest, e.g. `/static/`.
+    pub public_path: String,
+    /// Whether `Link` headers with preloads are added to responses.
+    pub link_header: bool,
+}
+
+impl AssetManifest {
+    /// Creates a manifest config with the given path, `/` public path and `Link` headers enabled.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            public_path: "/".to_string(),
+            link_header: true,
+        }
+    }
+}
+
+pub(crate) struct Manifest {
+    // Canonical path, so js worker can read the manifest regardless of its working directory.
+    pub path: PathBuf,
+    chunks: HashMap<String, Chunk>,
+    link_header: bool,
+}
+
+// Paths are resolved against the public path once on load.
+struct Chunk {
+    file: String,
+    imports: Vec<String>,
+    css: Vec<String>,
+    // Chunks of Vite are ES modules, chunks of a flat webpack manifest are classic scripts.
+    module: bool,
+}
+
+#[derive(Default)]
+struct Preloads {
+    seen: HashSet<String>,
+    modules: Vec<String>,
+    scripts: Vec<String>,
+    styles: Vec<String>,
+}
+
+impl Preloads {
+    fn add(&mut self, url: &str, module: bool) {
+        if !self.seen.insert(url.to_string()) {
+            return;
+        }
+        let path = url.split(&['?', '#'][..]).next().unwrap_or(url);
+        if path.ends_with(".js") || path.ends_with(".mjs") {
+            if module {
+                self.modules.push(url.to_string());
+            } else {
+                self.scripts.push(url.to_string());
+            }
+        } else if path.ends_with(".css") {
+            self.styles.push(url.to_string());
+        }
+    }
+}
+
+impl Manifest {
+    pub fn load(cfg: AssetManifest) -> Result<Self, InitializationError> {
+        let path = match fs::canonicalize(&cfg.path) {
+            Ok(path) => path,
+            Err(err) => return Err(InitializationError::InvalidAssetManifestPath(err)),
+        };
+        let json = match fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(err) => return Err(InitializationError::InvalidAssetManifestPath(err)),
+        };
+        Self::parse(path, &json, cfg)
+    }
+
+    fn parse(path: PathBuf, json: &str, cfg: AssetManifest) -> Result<Self, InitializationError> {
+        let entries = match serde_json::from_str(json) {
+            Ok(Value::Object(entries)) => entries,
+            Ok(_) => {
+                return Err(InitializationError::InvalidAssetManifest(
+                    "manifest must be an object".to_string(),
+                ))
+            }
+            Err(err) => return Err(InitializationError::InvalidAssetManifest(err.to_string())),
+        };
+
+        let public_path = if cfg.public_path.ends_with('/') {
+            cfg.public_path
+        } else {
+            format!("{}/", cfg.public_path)
+        };
+        let url = |file: &str| {
+            if file.starts_with('/') || file.contains("://") {
+                file.to_string()
+            } else {
+                format!("{}{}", public_path, file)
+            }
+        };
+        let strings = |value: Option<&Value>| match value {
+            Some(Value::Array(values)) => values
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        // Entries which are not chunks, e.g. `entrypoints` of webpack manifest, are skipped
+        let mut chunks = HashMap::new();
+        for (key, entry) in entries {
+            let chunk = match &entry {
+                Value::String(file) => Chunk {
+                    file: url(file),
+                    imports: Vec::new(),
+                    css: Vec::new(),
+                    module: false,
+                },
+                Value::Object(chunk) => match chunk.get("file").and_then(Value::as_str) {
+                    Some(file) => Chunk {
+                        file: url(file),
+                        imports: strings(chunk.get("imports")),
+                        css: strings(chunk.get("css"))
+                            .iter()
+                            .map(|css| url(css))
+                            .collect(),
+                        module: true,
+                    },
+                    None => continue,
+                },
+                _ => continue,
+            };
+            chunks.insert(key, chunk);
+        }
+
+        Ok(Self {
+            path,
+            chunks,
+            link_header: cfg.link_header,
+        })
+    }
+
+    // Adds preloads of chunks reported by the renderer to the head tags and headers.
+    pub fn preload(&self, head: &mut Head) {
+        let preloads = {
+            let mut preloads = Preloads::default();
+            let mut visited = HashSet::new();
+            for key in &head.chunks {
+                self.collect(key, &mut visited, &mut preloads);
+            }
+            preloads
+        };
+
+        for style in &preloads.styles {
+            head.head.push_str(&format!(
+                "<link rel=\"stylesheet\" href=\"{}\">",
+                template::escape_attribute(style)
+            ));
+            self.link(head, style, "rel=preload; as=style");
+        }
+        for module in &preloads.modules {
+            head.head.push_str(&format!(
+                "<link rel=\"modulepreload\" href=\"{}\">",
+                template::escape_attribute(module)
+            ));
+            self.link(head, module, "rel=modulepreload");
+        }
+        for script in &preloads.scripts {
+            head.head.push_str(&format!(
+                "<link rel=\"preload\" as=\"script\" href=\"{}\">",
+                template::escape_attribute(script)
+            ));
+            self.link(head, script, "rel=preload; as=script");
+        }
+    }
+
+    // Static imports are collected recursively, dynamic ones are loaded on demand.
+    fn collect<'a>(
+        &'a self,
+        key: &'a str,
+        visited: &mut HashSet<&'a str>,
+        preloads: &mut Preloads,
+    ) {
+        if !visited.insert(key) {
+            return;
+        }
+        let chunk = match self.chunks.get(key) {
+            Some(chunk) => chunk,
+            None => {
+                warn!("Chunk {} is not found in the asset manifest", key);
+                return;
+            }
+        };
+        preloads.add(&chunk.file, chunk.module);
+        for css in &chunk.css {
+            preloads.add(css, chunk.module);
+        }
+        for import in &chunk.imports {
+            self.collect(import, visited, preloads);
+        }
+    }
+
+    fn link(&self, head: &mut Head, url: &str, params: &str) {
+        if !self.link_header {
+            return;
+        }
+        let link = format!("<{}>; {}", encode_link_url(url), params);
+        match HeaderValue::from_str(&link) {
+            Ok(link) => {
+                head.headers.append(header::LINK, link);
+            }
+            Err(_) => warn!("Invalid value of Link header: {}", link),
+        }
+    }
+}
+
+// Percent-encodes characters which would end the URL of a `Link` header value or split it into
+// several links or parameters for lenient parsers, as well as the ones not allowed in a header.
+// Existing percent-encoded sequences are kept as is.
+fn encode_link_url(url: &str) -> String {
+    let mut encoded = String::with_capacity(url.len());
+    for byte in url.bytes() {
+        match byte {
+            b'<' | b'>' | b',' | b';' | b'"' | b'\\' | 0..=b' ' | 0x7f..=0xff => {
+                encoded.push_str(&format!("%{:02X}", byte))
+            }
+            byte => encoded.push(byte as char),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, StatusCode};
+
+    use super::*;
+
+    // Vite manifest of an app with a shared vendor chunk, a lazy page and an import cycle.
+    const VITE: &str = r#"{
+        "src/main.tsx": {
+            "file": "assets/main.4f2a.js",
+            "src": "src/main.tsx",
+            "isEntry": true,
+            "imports": ["_vendor.9c1b.js"],
+            "dynamicImports": ["src/pages/about.tsx"],
+            "css": ["assets/main.77aa.css"]
+        },
+        "src/pages/about.tsx": {
+            "file": "assets/about.12ef.js",
+            "imports": ["_vendor.9c1b.js", "_shared.0d3e.js"],
+            "css": ["assets/about.5b6c.css"]
+        },
+        "_vendor.9c1b.js": {
+            "file": "assets/vendor.9c1b.js",
+            "css": ["assets/main.77aa.css"]
+        },
+        "_shared.0d3e.js": {
+            "file": "assets/shared.0d3e.js",
+            "imports": ["src/pages/about.tsx"]
+        },
+        "logo.svg": {
+            "file": "assets/logo.8a8a.svg"
+        }
+    }"#;
+
+    // Flat manifest of webpack-manifest-plugin.
+    const WEBPACK: &str = r#"{
+        "main.js": "main.1a2b.js",
+        "main.css": "https://cdn.example.com/main.3c4d.css",
+        "about.js": "/js/about.5e6f.js?v=2",
+        "entrypoints": {"main": ["main.1a2b.js"]}
+    }"#;
+
+    fn manifest(json: &str, public_path: &str, link_header: bool) -> Manifest {
+        let cfg = AssetManifest {
+            public_path: public_path.to_string(),
+            link_header,
+            ..AssetManifest::new("manifest.json")
+        };
+        match Manifest::parse(PathBuf::from("manifest.json"), json, cfg) {
+            Ok(manifest) => manifest,
+            Err(err) => panic!("Manifest is not parsed: {}", err),
+        }
+    }
+
+    fn preload(manifest: &Manifest, chunks: &[&str]) -> Head {
+        let mut head = Head {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            head: String::new(),
+            chunks: chunks.iter().map(|chunk| chunk.to_string()).collect(),
+        };
+        manifest.preload(&mut head);
+        head
+    }
+
+    fn links(head: &Head) -> Vec<&str> {
+        head.headers
+            .get_all(header::LINK)
+            .iter()
+            .map(|link| link.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn collects_static_imports_of_vite_chunks() {
+        let manifest = manifest(VITE, "/static/", true);
+        let head = preload(&manifest, &["src/main.tsx"]);
+        assert_eq!(
+            head.head,
+            "<link rel=\"stylesheet\" href=\"/static/assets/main.77aa.css\">\
+             <link rel=\"modulepreload\" href=\"/static/assets/main.4f2a.js\">\
+             <link rel=\"modulepreload\" href=\"/static/assets/vendor.9c1b.js\">"
+        );
+        assert_eq!(
+            links(&head),
+            [
+                "</static/assets/main.77aa.css>; rel=preload; as=style",
+                "</static/assets/main.4f2a.js>; rel=modulepreload",
+                "</static/assets/vendor.9c1b.js>; rel=modulepreload",
+            ]
+        );
+    }
+
+    #[test]
+    fn dedups_files_shared_by_chunks() {
+        let manifest = manifest(VITE, "/", true);
+        let head = preload(
+            &manifest,
+            &["src/main.tsx", "src/pages/about.tsx", "src/main.tsx"],
+        );
+        assert_eq!(
+            links(&head),
+            [
+                "</assets/main.77aa.css>; rel=preload; as=style",
+                "</assets/about.5b6c.css>; rel=preload; as=style",
+                "</assets/main.4f2a.js>; rel=modulepreload",
+                "</assets/vendor.9c1b.js>; rel=modulepreload",
+                "</assets/about.12ef.js>; rel=modulepreload",
+                "</assets/shared.0d3e.js>; rel=modulepreload",
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_import_cycle() {
+        let manifest = manifest(VITE, "/", true);
+        let head = preload(&manifest, &["_shared.0d3e.js"]);
+        assert_eq!(
+            links(&head),
+            [
+                "</assets/about.5b6c.css>; rel=preload; as=style",
+                "</assets/main.77aa.css>; rel=preload; as=style",
+                "</assets/shared.0d3e.js>; rel=modulepreload",
+                "</assets/about.12ef.js>; rel=modulepreload",
+                "</assets/vendor.9c1b.js>; rel=modulepreload",
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_missing_chunks() {
+        let manifest = manifest(VITE, "/", true);
+        let head = preload(&manifest, &["src/missing.tsx", "_vendor.9c1b.js"]);
+        assert_eq!(
+            links(&head),
+            [
+                "</assets/main.77aa.css>; rel=preload; as=style",
+                "</assets/vendor.9c1b.js>; rel=modulepreload",
+            ]
+        );
+    }
+
+    #[test]
+    fn preloads_only_scripts_and_styles() {
+        let manifest = manifest(VITE, "/", true);
+        let head = preload(&manifest, &["logo.svg"]);
+        assert!(head.head.is_empty());
+        assert!(head.headers.is_empty());
+    }
+
+    #[test]
+    fn reads_flat_webpack_manifest() {
+        let manifest = manifest(WEBPACK, "/static", true);
+        assert!(!manifest.chunks.contains_key("entrypoints"));
+        let head = preload(&manifest, &["main.js", "main.css", "about.js"]);
+        assert_eq!(
+            head.head,
+            "<link rel=\"stylesheet\" href=\"https://cdn.example.com/main.3c4d.css\">\
+             <link rel=\"preload\" as=\"script\" href=\"/static/main.1a2b.js\">\
+             <link rel=\"preload\" as=\"script\" href=\"/js/about.5e6f.js?v=2\">"
+        );
+        assert_eq!(
+            links(&head),
+            [
+                "<https://cdn.example.com/main.3c4d.css>; rel=preload; as=style",
+                "</static/main.1a2b.js>; rel=preload; as=script",
+                "</js/about.5e6f.js?v=2>; rel=preload; as=script",
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_delimiters_of_link_header() {
+        let manifest = manifest(
+            r#"{"main.js": "a>b,c;d \"é%20.js", "main.css": "x<y.css"}"#,
+            "/",
+            true,
+        );
+        let head = preload(&manifest, &["main.js", "main.css"]);
+        assert_eq!(
+            links(&head),
+            [
+                "</x%3Cy.css>; rel=preload; as=style",
+                "</a%3Eb%2Cc%3Bd%20%22%C3%A9%20.js>; rel=preload; as=script",
+            ]
+        );
+        assert!(head.head.contains("href=\"/a&gt;b,c;d &quot;é%20.js\""));
+    }
+
+    #[test]
+    fn joins_public_path_with_and_without_trailing_slash() {
+        for public_path in &["/static", "/static/"] {
+            let manifest = manifest(WEBPACK, public_path, true);
+            assert_eq!(manifest.chunks["main.js"].file, "/static/main.1a2b.js");
+        }
+        let manifest = manifest(WEBPACK, "https://cdn.example.com/app", true);
+        assert_eq!(
+            manifest.chunks["main.js"].file,
+            "https://cdn.example.com/app/main.1a2b.js"
+        );
+        assert_eq!(manifest.chunks["about.js"].file, "/js/about.5e6f.js?v=2");
+    }
+
+    #[test]
+    fn adds_no_link_headers_if_disabled() {
+        let manifest = manifest(VITE, "/", false);
+        let head = preload(&manifest, &["src/main.tsx"]);
+        assert!(head.head.contains("rel=\"modulepreload\""));
+        assert!(head.headers.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_manifest() {
+        let reason = |json: &str| {
+            let cfg = AssetManifest::new("manifest.json");
+            match Manifest::parse(PathBuf::from("manifest.json"), json, cfg) {
+                Ok(_) => panic!("Manifest is parsed: {}", json),
+                Err(InitializationError::InvalidAssetManifest(reason)) => reason,
+                Err(err) => panic!("Unexpected error: {}", err),
+            }
+        };
+        assert_eq!(reason("[]"), "manifest must be an object");
+        assert!(!reason("{").is_empty());
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures_util::future;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{self, Instant},
+};
+
+use crate::{
+    error::{InitializationError, RenderingError},
+    health::Health,
+    worker::{Addr, Worker, WorkerConfig},
+    ConcurrencyLimit, Transport,
+};
+
+pub(crate) struct Pool {
+    workers: Vec<Arc<Worker>>,
+    // Slots of each worker, by its index. Empty if concurrency is not limited.
+    slots: Vec<Slots>,
+    next: AtomicUsize,
+    closing: AtomicBool,
+    limit: Option<ConcurrencyLimit>,
+    // A number of renders waiting for a free worker.
+    queued: AtomicUsize,
+}
+
+// Renders a worker can take on at once. The semaphore is fair, so renders waiting for a slot get
+// it in the order they came, and a new render can't take a slot while others are waiting for it.
+struct Slots {
+    semaphore: Arc<Semaphore>,
+    // A number of renders waiting for a slot of this worker.
+    waiting: AtomicUsize,
+}
+
+impl Pool {
+    pub async fn new(
+        port: u16,
+        transport: Transport,
+        size: usize,
+        limit: Option<ConcurrencyLimit>,
+        cfg: WorkerConfig,
+    ) -> Result<Self, InitializationError> {
+        if size == 0 {
+            return Err(InitializationError::InvalidPoolSize(size));
+        }
+        if let Some(limit) = &limit {
+            if limit.max_in_flight == 0 {
+                return Err(InitializationError::InvalidMaxInFlight(limit.max_in_flight));
+            }
+        }
+        if cfg.connections == 0 {
+            return Err(InitializationError::InvalidConnectionsPerWorker(
+                cfg.connections,
+            ));
+        }
+
+        if cfg!(not(unix)) {
+            if let Transport::UnixSocket { .. } = transport {
+                warn!("[RS] Unix domain sockets are not supported on this platform, using TCP");
+            }
+        }
+
+        let cfg = Arc::new(cfg);
+        let mut starting = Vec::with_capacity(size);
+        for idx in 0..size {
+            let addr = match Addr::new(&transport, port, idx) {
+                Some(addr) => addr,
+                None => return Err(InitializationError::InvalidPortRange { port, size }),
+            };
+            // Workers boot concurrently, so a slow renderer bundle doesn't multiply startup time
+            starting.push(tokio::spawn(Worker::new(idx, addr, cfg.clone())));
+        }
+
+        let mut workers = Vec::with_capacity(size);
+        for worker in starting {
+            let worker = worker.await.expect("Worker startup task panicked")?;
+            debug!("{worker}: Spawned", worker = worker);
+            workers.push(Arc::new(worker));
+        }
+
+        let slots = match &limit {
+            Some(limit) => (0..size)
+                .map(|_| Slots {
+                    semaphore: Arc::new(Semaphore::new(limit.max_in_flight)),
+                    waiting: AtomicUsize::new(0),
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            workers,
+            slots,
+            next: AtomicUsize::new(0),
+            closing: AtomicBool::new(false),
+            limit,
+            queued: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    // Pings workers concurrently, so a hanging worker doesn't delay the rest.
+    pub async fn health(&self, ping_timeout: Duration) -> Health {
+        let workers = self
+            .workers
+            .iter()
+            .map(|worker| worker.health(ping_timeout));
+        Health {
+            shutting_down: self.is_closing(),
+            workers: future::join_all(workers).await,
+        }
+    }
+
+    // Waits for in-flight renders to complete and terminates workers. Workers get killed once
+    // the deadline is exceeded.
+    pub async fn shutdown(&self, deadline: Instant) {
+        self.closing.store(true, Ordering::SeqCst);
+
+        let idle = future::join_all(self.workers.iter().map(|worker| worker.wait_idle()));
+        if time::timeout_at(deadline, idle).await.is_err() {
+            warn!("[RS] Shutdown deadline exceeded, aborting in-flight renders");
+        }
+
+        future::join_all(self.workers.iter().map(|worker| worker.shutdown(deadline))).await;
+    }
+
+    // Checks out a worker for a render. If the worker is at the concurrency limit, waits in the
+    // queue until it frees up.
+    pub async fn checkout(&self) -> Result<Lease, RenderingError> {
+        let idx = self.pick();
+        let worker = &self.workers[idx];
+        let (limit, slots) = match &self.limit {
+            Some(limit) => (limit, &self.slots[idx]),
+            None => return Ok(Lease::new(worker.clone(), None)),
+        };
+        // A worker which is not available is checked out regardless, so the render fails right
+        // away instead of waiting in the queue.
+        if !worker.is_available() {
+            return Ok(Lease::new(worker.clone(), None));
+        }
+        if let Ok(permit) = slots.semaphore.clone().try_acquire_owned() {
+            return Ok(Lease::new(worker.clone(), Some(permit)));
+        }
+
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= limit.max_queued {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(RenderingError::Overloaded);
+        }
+        let _queued = Queued(&self.queued);
+        slots.waiting.fetch_add(1, Ordering::SeqCst);
+        let _waiting = Queued(&slots.waiting);
+        match time::timeout(limit.queue_timeout, slots.semaphore.clone().acquire_owned()).await {
+            Ok(permit) => Ok(Lease::new(worker.clone(), Some(permit))),
+            Err(_) => Err(RenderingError::Overloaded),
+        }
+    }
+
+    // Picks an available worker with the least load, i.e. in-flight renders and renders waiting
+    // for it. Search starts from the next worker in a round-robin order, so idle workers are
+    // loaded evenly.
+    fn pick(&self) -> usize {
+        let len = self.workers.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+        let mut candidate = start;
+        for offset in 1..len {
+            let idx = (start + offset) % len;
+            let is_better = match (
+                self.workers[idx].is_available(),
+                self.workers[candidate].is_available(),
+            ) {
+                (true, false) => true,
+                (false, _) => false,
+                (true, true) => self.load(idx) < self.load(candidate),
+            };
+            if is_better {
+                candidate = idx;
+            }
+        }
+        candidate
+    }
+
+    fn load(&self, idx: usize) -> usize {
+        let waiting = match self.slots.get(idx) {
+            Some(slots) => slots.waiting.load(Ordering::SeqCst),
+            None => 0,
+        };
+        self.workers[idx].in_flight() + waiting
+    }
+}
+
+// Keeps a counter of waiting renders incremented while a render waits for a free worker.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Keeps a worker's in-flight counter incremented and its slot, if any, taken for as long as
+// a render is in progress.
+pub(crate) struct Lease {
+    worker: Arc<Worker>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Lease {
+    fn new(worker: Arc<Worker>, permit: Option<OwnedSemaphorePermit>) -> Self {
+        worker.in_flight.fetch_add(1, Ordering::SeqCst);
+        Self {
+            worker,
+            _permit: permit,
+        }
+    }
+
+    // A handle on the worker which outlives the lease, e.g. for background checks.
+    pub fn handle(&self) -> Arc<Worker> {
+        self.worker.clone()
+    }
+}
+
+impl Deref for Lease {
+    type Target = Worker;
+
+    fn deref(&self) -> &Worker {
+        &self.worker
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        // The slot is freed afterwards, along with the permit
+        self.worker.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{JsWorkerLog, RestartPolicy};
+
+    async fn pool(max_in_flight: usize, max_queued: usize, queue_timeout: u64) -> Arc<Pool> {
+        let fixture = |path: &str| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(path);
+        let limit = ConcurrencyLimit {
+            max_in_flight,
+            max_queued,
+            queue_timeout: Duration::from_millis(queue_timeout),
+        };
+        let cfg = WorkerConfig {
+            js_worker: fixture("js/worker.js"),
+            js_worker_log: JsWorkerLog::Minimal,
+            global_js_renderer: Some(fixture("tests/fixtures/renderer.js")),
+            asset_manifest: None,
+            source_maps: false,
+            startup_timeout: Duration::from_secs(10),
+            ping_timeout: Duration::from_secs(1),
+            connections: 1,
+            restart_policy: RestartPolicy::Never,
+            on_worker_event: None,
+            circuit_breaker: None,
+        };
+        let pool = Pool::new(0, Transport::Tcp, 1, Some(limit), cfg).await;
+        Arc::new(pool.expect("Pool is not started"))
+    }
+
+    // Checks out a worker in the background.
+    fn checkout(pool: &Arc<Pool>) -> tokio::task::JoinHandle<Result<Lease, RenderingError>> {
+        let pool = pool.clone();
+        tokio::spawn(async move { pool.checkout().await })
+    }
+
+    // Resolves once the given number of renders wait in the queue.
+    async fn queued(pool: &Pool, renders: usize) {
+        while pool.queued.load(Ordering::SeqCst) != renders {
+            time::delay_for(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatches_queued_renders_in_order() {
+        let pool = pool(1, 10, 5_000).await;
+        let lease = pool.checkout().await.unwrap();
+        let mut waiting = Vec::new();
+        for idx in 0..3 {
+            waiting.push(checkout(&pool));
+            queued(&pool, idx + 1).await;
+        }
+
+        // A render coming right after a slot is freed doesn't take it from the waiting ones
+        drop(lease);
+        let late = time::timeout(Duration::from_millis(50), pool.checkout()).await;
+        assert!(late.is_err());
+
+        // Each waiting render gets the slot only after the ones queued before it release it
+        for idx in 0..waiting.len() {
+            let lease = (&mut waiting[idx]).await.unwrap().unwrap();
+            for later in &mut waiting[idx + 1..] {
+                let pending = time::timeout(Duration::from_millis(20), later).await;
+                assert!(pending.is_err());
+            }
+            assert_eq!(pool.queued.load(Ordering::SeqCst), 2 - idx);
+            drop(lease);
+        }
+    }
+
+    #[tokio::test]
+    async fn released_slots_wake_as_many_waiting_renders() {
+        let pool = pool(3, 10, 1_000).await;
+        let mut leases = Vec::new();
+        for _ in 0..3 {
+            leases.push(pool.checkout().await.unwrap());
+        }
+        let waiting: Vec<_> = (0..3).map(|_| checkout(&pool)).collect();
+        queued(&pool, 3).await;
+
+        let released_at = Instant::now();
+        drop(leases);
+        let mut leases = Vec::new();
+        for lease in waiting {
+            leases.push(lease.await.unwrap().unwrap());
+        }
+        assert!(released_at.elapsed() < Duration::from_millis(500));
+        assert_eq!(pool.workers[0].in_flight(), 3);
+    }
+
+    #[tokio::test]
+    async fn rejects_renders_once_queue_is_full() {
+        let pool = pool(1, 1, 5_000).await;
+        let lease = pool.checkout().await.unwrap();
+        let waiting = checkout(&pool);
+        queued(&pool, 1).await;
+
+        let started_at = Instant::now();
+        assert!(matches!(
+            pool.checkout().await,
+            Err(RenderingError::Overloaded)
+        ));
+        assert!(started_at.elapsed() < Duration::from_millis(100));
+        assert_eq!(pool.queued.load(Ordering::SeqCst), 1);
+
+        drop(lease);
+        assert!(waiting.await.unwrap().is_ok());
+        assert_eq!(pool.queued.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_renders_waiting_too_long() {
+        let pool = pool(1, 10, 100).await;
+        let lease = pool.checkout().await.unwrap();
+
+        let started_at = Instant::now();
+        assert!(matches!(
+            pool.checkout().await,
+            Err(RenderingError::Overloaded)
+        ));
+        assert!(started_at.elapsed() >= Duration::from_millis(100));
+        assert_eq!(pool.queued.load(Ordering::SeqCst), 0);
+        assert_eq!(pool.slots[0].waiting.load(Ordering::SeqCst), 0);
+
+        // The render which gave up doesn't hold the slot
+        drop(lease);
+        assert!(pool.checkout().await.is_ok());
+    }
+}
@@ -0,0 +1,202 @@
+// Rust side and js worker exchange frames over long-lived connections. Each frame consists of
+// a 32-bit payload length, 8-bit frame type, 128-bit id of the rendering request and the payload
+// itself. Frames of concurrent renders are interleaved on the same connection.
+//
+// Rust side sends:
+// - RENDER: a rendering request, which is a JSON-encoded meta and data, each prefixed with its
+//   32-bit length
+// - CANCEL: the response is not needed anymore (e.g. render timed out or the stream is dropped)
+// - PING: a health check, not related to any render
+//
+// Js worker responds with:
+// - HEAD: JSON-encoded meta of the response (status, headers and head tags)
+// - CHUNK: a chunk of the response body
+// - END: the response is complete
+// - ERROR: JSON-encoded error, can be sent instead of HEAD or at any point after it
+// - PONG: a response to PING with the same id
+
+use std::{convert::TryFrom, io};
+
+use bytes::Bytes;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::{
+    error::RenderingError,
+    json::{self, Escaping},
+};
+
+const FRAME_LENGTH_SIZE: usize = 4;
+const FRAME_ID_SIZE: usize = 16;
+const FRAME_HEADER_SIZE: usize = FRAME_LENGTH_SIZE + 1 + FRAME_ID_SIZE;
+const RENDER_LENGTH_SIZE: usize = 4;
+
+const FRAME_HEAD: u8 = 1;
+const FRAME_CHUNK: u8 = 2;
+const FRAME_END: u8 = 3;
+const FRAME_ERROR: u8 = 4;
+pub(crate) const FRAME_RENDER: u8 = 5;
+pub(crate) const FRAME_CANCEL: u8 = 6;
+pub(crate) const FRAME_PING: u8 = 7;
+const FRAME_PONG: u8 = 8;
+
+pub(crate) enum Frame {
+    Head(Vec<u8>),
+    Chunk(Bytes),
+    End,
+    Error(Vec<u8>),
+    Pong,
+}
+
+impl Frame {
+    // Size of the payload held in memory.
+    pub fn size(&self) -> usize {
+        match self {
+            Frame::Head(payload) | Frame::Error(payload) => payload.len(),
+            Frame::Chunk(chunk) => chunk.len(),
+            Frame::End | Frame::Pong => 0,
+        }
+    }
+}
+
+// An outgoing frame, header included, so it's written to the socket at once.
+pub(crate) struct OutgoingFrame(Bytes);
+
+impl OutgoingFrame {
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.0).await
+    }
+}
+
+pub(crate) fn encode_frame(
+    kind: u8,
+    id: &Uuid,
+    payload: &[u8],
+) -> Result<OutgoingFrame, RenderingError> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.resize(FRAME_HEADER_SIZE, 0);
+    frame.extend_from_slice(payload);
+    patch_header(&mut frame, kind, id)?;
+    Ok(OutgoingFrame(Bytes::from(frame)))
+}
+
+// Fills in the header placeholder at the start of the frame once the payload is written after it.
+fn patch_header(frame: &mut [u8], kind: u8, id: &Uuid) -> Result<(), RenderingError> {
+    let len = match u32::try_from(frame.len() - FRAME_HEADER_SIZE) {
+        Ok(len) => len,
+        Err(_) => {
+            return Err(RenderingError::RenderRequestError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "rendering request is too large",
+            )))
+        }
+    };
+    frame[..FRAME_LENGTH_SIZE].copy_from_slice(&len.to_be_bytes());
+    frame[FRAME_LENGTH_SIZE] = kind;
+    frame[FRAME_LENGTH_SIZE + 1..FRAME_HEADER_SIZE].copy_from_slice(id.as_bytes());
+    Ok(())
+}
+
+// A rendering request encoded as a frame, ready to be sent to the worker.
+//
+// The frame is an owned buffer rather than being serialized into the write buffer of a connection,
+// because it's needed before and apart from any connection: the cache key is a hash of its payload,
+// which is computed before a worker is checked out, and the same frame is sent again by background
+// revalidation of a stale response. It's shared via `Bytes`, so neither of those copies it, and
+// it's written to the socket as is.
+#[derive(Clone)]
+pub(crate) struct RenderFrame {
+    id: Uuid,
+    frame: Bytes,
+}
+
+impl RenderFrame {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    // Payload doesn't depend on the request id, so identical requests have identical payloads.
+    pub fn payload(&self) -> &[u8] {
+        &self.frame[FRAME_HEADER_SIZE..]
+    }
+
+    pub fn frame(&self) -> OutgoingFrame {
+        OutgoingFrame(self.frame.clone())
+    }
+}
+
+// Serializes meta and data of a rendering request right into the frame, after placeholders of the
+// frame header and lengths, which are filled in afterwards. Data is serialized once, escaped so
+// the renderer can inline it into a script tag as is.
+pub(crate) fn encode_render<D: Serialize>(
+    id: Uuid,
+    meta: &Value,
+    data: &D,
+) -> Result<RenderFrame, RenderingError> {
+    let lengths = FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + 2 * RENDER_LENGTH_SIZE;
+    let mut frame = vec![0; lengths.end];
+    if let Err(err) = serde_json::to_writer(&mut frame, meta) {
+        return Err(RenderingError::UrlSerializationError(err));
+    }
+    let meta_len = frame.len() - lengths.end;
+    if let Err(err) = json::to_writer(&mut frame, data, Escaping::ScriptTag) {
+        return Err(RenderingError::DataSerializationError(err));
+    }
+    let data_len = frame.len() - lengths.end - meta_len;
+    patch_header(&mut frame, FRAME_RENDER, &id)?;
+    frame[lengths.start..lengths.start + RENDER_LENGTH_SIZE]
+        .copy_from_slice(&(meta_len as u32).to_be_bytes());
+    frame[lengths.start + RENDER_LENGTH_SIZE..lengths.end]
+        .copy_from_slice(&(data_len as u32).to_be_bytes());
+    Ok(RenderFrame {
+        id,
+        frame: Bytes::from(frame),
+    })
+}
+
+pub(crate) async fn read_frame<R>(reader: &mut R) -> Result<(Uuid, Frame), RenderingError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0; FRAME_HEADER_SIZE];
+    if let Err(err) = reader.read_exact(&mut header).await {
+        return Err(RenderingError::RenderResponseError(err));
+    }
+
+    let mut len = [0; FRAME_LENGTH_SIZE];
+    len.copy_from_slice(&header[..FRAME_LENGTH_SIZE]);
+    let len = match usize::try_from(u32::from_be_bytes(len)) {
+        Ok(len) => len,
+        Err(_) => {
+            return Err(RenderingError::InvalidResponse(
+                "frame is too large".to_string(),
+            ))
+        }
+    };
+
+    let mut id = [0; FRAME_ID_SIZE];
+    id.copy_from_slice(&header[FRAME_LENGTH_SIZE + 1..]);
+    let id = Uuid::from_bytes(id);
+
+    let mut payload = vec![0; len];
+    if let Err(err) = reader.read_exact(&mut payload).await {
+        return Err(RenderingError::RenderResponseError(err));
+    }
+
+    let frame = match header[FRAME_LENGTH_SIZE] {
+        FRAME_HEAD => Frame::Head(payload),
+        FRAME_CHUNK => Frame::Chunk(Bytes::from(payload)),
+        FRAME_END => Frame::End,
+        FRAME_ERROR => Frame::Error(payload),
+        FRAME_PONG => Frame::Pong,
+        kind => {
+            return Err(RenderingError::InvalidResponse(format!(
+                "unknown frame type: {}",
+                kind
+            )))
+        }
+    };
+    Ok((id, frame))
+}
@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+
+use http::{
+    header::{self, HeaderName},
+    request::Parts,
+    HeaderMap, Method, Request, Uri,
+};
+use serde_json::{Map, Value};
+
+/// An incoming request to render a response for, passed to [`ssr.render`](crate::Ssr::render).
+///
+/// Can be created from [`Uri`](http::Uri), in which case the JS renderer receives only a url, or
+/// from [`Request`](http::Request) and [`Parts`](http::request::Parts), in which case method,
+/// headers and cookies are forwarded to the JS renderer as well. Forwarded headers are filtered
+/// according to [`SsrConfig::forwarded_headers`](crate::SsrConfig::forwarded_headers).
+#[derive(Clone, Copy)]
+pub struct RenderRequest<'a> {
+    /// Uri of the request.
+    pub uri: &'a Uri,
+    /// HTTP method of the request.
+    pub method: Option<&'a Method>,
+    /// Headers of the request.
+    pub headers: Option<&'a HeaderMap>,
+}
+
+impl<'a> From<&'a Uri> for RenderRequest<'a> {
+    fn from(uri: &'a Uri) -> Self {
+        Self {
+            uri,
+            method: None,
+            headers: None,
+        }
+    }
+}
+
+impl<'a> From<&'a Parts> for RenderRequest<'a> {
+    fn from(parts: &'a Parts) -> Self {
+        Self {
+            uri: &parts.uri,
+            method: Some(&parts.method),
+            headers: Some(&parts.headers),
+        }
+    }
+}
+
+impl<'a, B> From<&'a Request<B>> for RenderRequest<'a> {
+    fn from(req: &'a Request<B>) -> Self {
+        Self {
+            uri: req.uri(),
+            method: Some(req.method()),
+            headers: Some(req.headers()),
+        }
+    }
+}
+
+impl<'a> RenderRequest<'a> {
+    pub(crate) fn method_json(&self) -> Value {
+        match self.method {
+            Some(method) => Value::String(method.as_str().to_string()),
+            None => Value::Null,
+        }
+    }
+
+    // Multiple values of the same header are joined the same way Node.js does it for
+    // `IncomingMessage.headers`. Values that are not valid visible ASCII are skipped.
+    pub(crate) fn headers_json(&self, filter: &HeaderFilter) -> Value {
+        let mut headers = BTreeMap::new();
+        if let Some(map) = self.headers {
+            for name in map.keys() {
+                if !filter.allows(name) {
+                    continue;
+                }
+                let separator = if name == header::COOKIE { "; " } else { ", " };
+                let values: Vec<&str> = map
+                    .get_all(name)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .collect();
+                if !values.is_empty() {
+                    headers.insert(name.as_str(), values.join(separator));
+                }
+            }
+        }
+        json!(headers)
+    }
+
+    pub(crate) fn cookies_json(&self, filter: &HeaderFilter) -> Value {
+        let mut cookies = Map::new();
+        if let Some(map) = self.headers {
+            if filter.allows(&header::COOKIE) {
+                for value in map.get_all(header::COOKIE) {
+                    let value = match value.to_str() {
+                        Ok(value) => value,
+                        Err(_) => continue,
+                    };
+                    for pair in value.split(';') {
+                        let mut pair = pair.splitn(2, '=');
+                        let name = pair.next().unwrap_or("").trim();
+                        let value = match pair.next() {
+                            Some(value) => value.trim(),
+                            None => continue,
+                        };
+                        if !name.is_empty() && !cookies.contains_key(name) {
+                            cookies.insert(name.to_string(), Value::String(value.to_string()));
+                        }
+                    }
+                }
+            }
+        }
+        Value::Object(cookies)
+    }
+}
+
+/// Defines which headers of [`RenderRequest`](RenderRequest) are forwarded to JS renderer.
+/// Cookies are forwarded only if `Cookie` header is allowed.
+#[derive(Clone, Debug)]
+pub enum HeaderFilter {
+    /// No headers are forwarded.
+    None,
+    /// All headers are forwarded.
+    All,
+    /// Only listed headers are forwarded.
+    Allow(Vec<HeaderName>),
+    /// All headers except listed ones are forwarded.
+    Deny(Vec<HeaderName>),
+}
+
+impl HeaderFilter {
+    pub(crate) fn allows(&self, name: &HeaderName) -> bool {
+        match self {
+            HeaderFilter::None => false,
+            HeaderFilter::All => true,
+            HeaderFilter::Allow(names) => names.contains(name),
+            HeaderFilter::Deny(names) => !names.contains(name),
+        }
+    }
+}
+
+impl Default for HeaderFilter {
+    /// Forwards all headers, except for credentials which are not supposed to be used
+    /// during rendering: `Authorization` and `Proxy-Authorization`.
+    fn default() -> Self {
+        HeaderFilter::Deny(vec![header::AUTHORIZATION, header::PROXY_AUTHORIZATION])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn headers(entries: &[(HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in entries {
+            headers.append(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn request<'a>(uri: &'a Uri, headers: &'a HeaderMap) -> RenderRequest<'a> {
+        RenderRequest {
+            uri,
+            method: Some(&Method::GET),
+            headers: Some(headers),
+        }
+    }
+
+    #[test]
+    fn default_filter_denies_credentials() {
+        let filter = HeaderFilter::default();
+        assert!(!filter.allows(&header::AUTHORIZATION));
+        assert!(!filter.allows(&header::PROXY_AUTHORIZATION));
+        assert!(filter.allows(&header::COOKIE));
+        assert!(filter.allows(&header::ACCEPT_LANGUAGE));
+        assert!(filter.allows(&HeaderName::from_static("x-request-id")));
+    }
+
+    #[test]
+    fn default_filter_strips_credentials_from_forwarded_headers() {
+        let uri = Uri::from_static("/");
+        let map = headers(&[
+            (header::AUTHORIZATION, "Bearer secret"),
+            (header::PROXY_AUTHORIZATION, "Basic secret"),
+            (header::ACCEPT_LANGUAGE, "en"),
+        ]);
+        let json = request(&uri, &map).headers_json(&HeaderFilter::default());
+        assert_eq!(json, json!({"accept-language": "en"}));
+        assert!(!json.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn filters_headers() {
+        let uri = Uri::from_static("/");
+        let map = headers(&[(header::ACCEPT, "text/html"), (header::COOKIE, "a=1")]);
+        let req = request(&uri, &map);
+
+        assert_eq!(req.headers_json(&HeaderFilter::None), json!({}));
+        assert_eq!(req.cookies_json(&HeaderFilter::None), json!({}));
+        assert_eq!(
+            req.headers_json(&HeaderFilter::All),
+            json!({"accept": "text/html", "cookie": "a=1"})
+        );
+        let allow = HeaderFilter::Allow(vec![header::ACCEPT]);
+        assert_eq!(req.headers_json(&allow), json!({"accept": "text/html"}));
+        assert_eq!(req.cookies_json(&allow), json!({}));
+        let deny = HeaderFilter::Deny(vec![header::ACCEPT]);
+        assert_eq!(req.headers_json(&deny), json!({"cookie": "a=1"}));
+        assert_eq!(req.cookies_json(&deny), json!({"a": "1"}));
+    }
+
+    #[test]
+    fn joins_multiple_values() {
+        let uri = Uri::from_static("/");
+        let map = headers(&[
+            (header::ACCEPT, "text/html"),
+            (header::ACCEPT, "*/*"),
+            (header::COOKIE, "a=1"),
+            (header::COOKIE, "b=2"),
+        ]);
+        let json = request(&uri, &map).headers_json(&HeaderFilter::All);
+        assert_eq!(
+            json,
+            json!({"accept": "text/html, */*", "cookie": "a=1; b=2"})
+        );
+    }
+
+    #[test]
+    fn skips_non_ascii_values() {
+        let uri = Uri::from_static("/");
+        let mut map = HeaderMap::new();
+        map.insert(
+            header::ACCEPT,
+            HeaderValue::from_bytes(b"caf\xc3\xa9").unwrap(),
+        );
+        let json = request(&uri, &map).headers_json(&HeaderFilter::All);
+        assert_eq!(json, json!({}));
+    }
+
+    #[test]
+    fn parses_cookies() {
+        let uri = Uri::from_static("/");
+        let map = headers(&[
+            (header::COOKIE, "a=1; b = 2 ;invalid; =empty; c=x=y"),
+            (header::COOKIE, "a=shadowed; d="),
+        ]);
+        let json = request(&uri, &map).cookies_json(&HeaderFilter::default());
+        assert_eq!(json, json!({"a": "1", "b": "2", "c": "x=y", "d": ""}));
+    }
+
+    #[test]
+    fn forwards_nothing_but_url_from_uri() {
+        let uri = Uri::from_static("/path?query");
+        let req = RenderRequest::from(&uri);
+        assert_eq!(req.method_json(), Value::Null);
+        assert_eq!(req.headers_json(&HeaderFilter::All), json!({}));
+        assert_eq!(req.cookies_json(&HeaderFilter::All), json!({}));
+    }
+}
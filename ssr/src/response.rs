@@ -0,0 +1,404 @@
+use std::{
+    convert::TryFrom,
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_util::{
+    future,
+    stream::{self, BoxStream, Stream, StreamExt},
+};
+use http::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap, Response, StatusCode,
+};
+use serde_json::{Map, Value};
+
+use crate::error::{JsError, JsErrorOrigin, JsStackFrame, RenderingError};
+
+/// A structured response of a JS renderer, returned by
+/// [`ssr.render_response`](crate::Ssr::render_response).
+///
+/// JS renderer can return either a string, which becomes a body of `200 OK` response, or an
+/// object of the following shape, or a `Promise` resolving to either of them:
+///
+/// ```js
+/// {
+///   status: 404,                                // optional, defaults to 200
+///   headers: {"Cache-Control": "no-cache"},     // optional, values can be arrays of strings
+///   head: "<title>Not Found</title>",           // optional, either a string or an array of strings
+///   body: "<div>Not Found</div>",               // optional, defaults to an empty string
+/// }
+/// ```
+///
+/// Body can also be a Node.js readable stream, an async iterable of strings or buffers, or a
+/// pipeable stream (e.g. returned by React's `renderToPipeableStream`). Such bodies are
+/// transferred in chunks, which can be consumed as they arrive via
+/// [`ssr.render_stream`](crate::Ssr::render_stream).
+#[derive(Clone, Debug)]
+pub struct RenderResponse {
+    /// Status code of the response.
+    pub status: StatusCode,
+    /// Headers of the response.
+    pub headers: HeaderMap,
+    /// Tags that should be placed into the `<head>` of the document.
+    pub head: String,
+    /// Rendered markup.
+    pub body: String,
+}
+
+/// A response of a JS renderer with a body streamed as it's being rendered, returned by
+/// [`ssr.render_stream`](crate::Ssr::render_stream).
+///
+/// Status code, headers and head tags are available right away, while body chunks are yielded
+/// by the [`Stream`](Stream) implementation. Rendering is aborted once the stream
+/// is dropped.
+pub struct RenderStream {
+    /// Status code of the response.
+    pub status: StatusCode,
+    /// Headers of the response.
+    pub headers: HeaderMap,
+    /// Tags that should be placed into the `<head>` of the document.
+    pub head: String,
+    body: BoxStream<'static, Result<Bytes, RenderingError>>,
+}
+
+impl RenderStream {
+    pub(crate) fn new(head: Head, body: BoxStream<'static, Result<Bytes, RenderingError>>) -> Self {
+        Self {
+            status: head.status,
+            headers: head.headers,
+            head: head.head,
+            body,
+        }
+    }
+
+    // A stream of a response which is rendered already, e.g. a fallback shell.
+    pub(crate) fn from_response(res: RenderResponse) -> Self {
+        Self {
+            status: res.status,
+            headers: res.headers,
+            head: res.head,
+            body: stream::once(future::ready(Ok(Bytes::from(res.body)))).boxed(),
+        }
+    }
+
+    /// Waits until rendering is complete and collects the whole body into a
+    /// [`RenderResponse`](RenderResponse).
+    pub async fn into_response(mut self) -> Result<RenderResponse, RenderingError> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.body.next().await {
+            body.extend_from_slice(&chunk?);
+        }
+        let body = match String::from_utf8(body) {
+            Ok(body) => body,
+            Err(_) => return Err(invalid("body is not a valid UTF-8")),
+        };
+        Ok(RenderResponse {
+            status: self.status,
+            headers: self.headers,
+            head: self.head,
+            body,
+        })
+    }
+}
+
+impl Stream for RenderStream {
+    type Item = Result<Bytes, RenderingError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.body.as_mut().poll_next(cx)
+    }
+}
+
+impl fmt::Debug for RenderStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RenderStream")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("head", &self.head)
+            .finish()
+    }
+}
+
+impl From<RenderResponse> for Response<String> {
+    fn from(res: RenderResponse) -> Self {
+        let mut http_res = Response::new(res.body);
+        *http_res.status_mut() = res.status;
+        *http_res.headers_mut() = res.headers;
+        http_res
+    }
+}
+
+// Meta of the response, sent by js worker before the body.
+pub(crate) struct Head {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub head: String,
+    // Keys of the asset manifest used by the page.
+    pub chunks: Vec<String>,
+}
+
+pub(crate) fn parse_head(meta: &[u8]) -> Result<Head, RenderingError> {
+    let meta: Value = match serde_json::from_slice(meta) {
+        Ok(meta) => meta,
+        Err(err) => return Err(invalid(&format!("response meta is not a valid JSON: {}", err))),
+    };
+    if !meta.is_object() {
+        return Err(invalid("response meta must be an object"));
+    }
+
+    let status = match meta.get("status") {
+        None | Some(Value::Null) => StatusCode::OK,
+        Some(status) => match status.as_u64().and_then(|s| u16::try_from(s).ok()) {
+            Some(status) => match StatusCode::from_u16(status) {
+                Ok(status) => status,
+                Err(_) => return Err(invalid(&format!("invalid status: {}", status))),
+            },
+            None => return Err(invalid(&format!("invalid status: {}", status))),
+        },
+    };
+
+    let mut headers = HeaderMap::new();
+    match meta.get("headers") {
+        None | Some(Value::Null) => {}
+        Some(Value::Object(entries)) => {
+            for (name, value) in entries {
+                let name = match HeaderName::from_bytes(name.as_bytes()) {
+                    Ok(name) => name,
+                    Err(_) => return Err(invalid(&format!("invalid header name: {}", name))),
+                };
+                let values = match value {
+                    Value::Array(values) => values.iter().collect(),
+                    value => vec![value],
+                };
+                for value in values {
+                    headers.append(name.clone(), header_value(&name, value)?);
+                }
+            }
+        }
+        Some(_) => return Err(invalid("headers must be an object")),
+    }
+
+    let head = match meta.get("head") {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(head)) => head.clone(),
+        Some(_) => return Err(invalid("head must be a string")),
+    };
+
+    let chunks = match meta.get("chunks") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(chunks)) => {
+            let chunks = chunks
+                .iter()
+                .map(|chunk| chunk.as_str().map(str::to_string));
+            match chunks.collect() {
+                Some(chunks) => chunks,
+                None => return Err(invalid("chunks must be an array of strings")),
+            }
+        }
+        Some(_) => return Err(invalid("chunks must be an array of strings")),
+    };
+
+    Ok(Head {
+        status,
+        headers,
+        head,
+        chunks,
+    })
+}
+
+pub(crate) fn parse_error(payload: &[u8]) -> RenderingError {
+    let error: Value = match serde_json::from_slice(payload) {
+        Ok(error) => error,
+        Err(err) => return invalid(&format!("error is not a valid JSON: {}", err)),
+    };
+    match error.get("error") {
+        Some(error) => RenderingError::JsExceptionDuringRendering(Box::new(js_error(error))),
+        None => invalid("error is missing"),
+    }
+}
+
+// Error is parsed leniently, since anything can be thrown in JS, e.g. a string or `null`.
+fn js_error(error: &Value) -> JsError {
+    let string = |key| match error.get(key) {
+        Some(Value::String(value)) => Some(value.clone()),
+        _ => None,
+    };
+    let frames = match error.get("frames") {
+        Some(Value::Array(frames)) => frames.iter().map(js_stack_frame).collect(),
+        _ => Vec::new(),
+    };
+    let cause = match error.get("cause") {
+        None | Some(Value::Null) => None,
+        Some(cause) => Some(Box::new(js_error(cause))),
+    };
+    let properties = match error.get("properties") {
+        Some(Value::Object(properties)) => properties.clone(),
+        _ => Map::new(),
+    };
+    let origin = match error.get("origin").and_then(Value::as_str) {
+        Some("renderer") => JsErrorOrigin::Renderer,
+        _ => JsErrorOrigin::Worker,
+    };
+    JsError {
+        name: string("name").unwrap_or_else(|| "Error".to_string()),
+        message: string("message").unwrap_or_else(|| match error {
+            Value::String(message) => message.clone(),
+            Value::Object(_) => String::new(),
+            error => error.to_string(),
+        }),
+        stack: string("stack"),
+        frames,
+        cause,
+        properties,
+        origin,
+    }
+}
+
+fn js_stack_frame(frame: &Value) -> JsStackFrame {
+    let string = |key| frame.get(key).and_then(Value::as_str).map(str::to_string);
+    let number = |key| {
+        frame
+            .get(key)
+            .and_then(Value::as_u64)
+            .and_then(|n| u32::try_from(n).ok())
+    };
+    JsStackFrame {
+        function: string("function"),
+        file: string("file"),
+        line: number("line"),
+        column: number("column"),
+    }
+}
+
+fn header_value(name: &HeaderName, value: &Value) -> Result<HeaderValue, RenderingError> {
+    let value = match value {
+        Value::String(value) => HeaderValue::from_str(value),
+        Value::Number(value) => HeaderValue::from_str(&value.to_string()),
+        _ => return Err(invalid(&format!("invalid value of header {}", name))),
+    };
+    value.map_err(|_| invalid(&format!("invalid value of header {}", name)))
+}
+
+fn invalid(reason: &str) -> RenderingError {
+    RenderingError::InvalidResponse(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header;
+
+    use super::*;
+
+    fn parse(meta: &str) -> Result<Head, String> {
+        match parse_head(meta.as_bytes()) {
+            Ok(head) => Ok(head),
+            Err(RenderingError::InvalidResponse(reason)) => Err(reason),
+            Err(err) => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    fn reason(meta: &str) -> String {
+        match parse(meta) {
+            Ok(_) => panic!("Meta is parsed: {}", meta),
+            Err(reason) => reason,
+        }
+    }
+
+    #[test]
+    fn parses_empty_meta_with_defaults() {
+        let head = parse("{}").unwrap();
+        assert_eq!(head.status, StatusCode::OK);
+        assert!(head.headers.is_empty());
+        assert_eq!(head.head, "");
+        assert!(head.chunks.is_empty());
+
+        let head = parse(r#"{"status":null,"headers":null,"head":null,"chunks":null}"#).unwrap();
+        assert_eq!(head.status, StatusCode::OK);
+    }
+
+    #[test]
+    fn parses_full_meta() {
+        let head = parse(
+            r#"{
+                "status": 404,
+                "headers": {"Cache-Control": "no-cache", "Set-Cookie": ["a=1", "b=2"], "X-N": 1},
+                "head": "<title>Not Found</title>",
+                "chunks": ["src/main.tsx"]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(head.status, StatusCode::NOT_FOUND);
+        assert_eq!(head.headers[header::CACHE_CONTROL], "no-cache");
+        let cookies: Vec<_> = head.headers.get_all(header::SET_COOKIE).iter().collect();
+        assert_eq!(cookies, ["a=1", "b=2"]);
+        assert_eq!(head.headers["x-n"], "1");
+        assert_eq!(head.head, "<title>Not Found</title>");
+        assert_eq!(head.chunks, ["src/main.tsx"]);
+    }
+
+    #[test]
+    fn rejects_malformed_meta() {
+        assert!(reason("").starts_with("response meta is not a valid JSON"));
+        assert!(reason(r#"{"status":"#).starts_with("response meta is not a valid JSON"));
+        assert!(matches!(
+            parse_head(&[0xff]),
+            Err(RenderingError::InvalidResponse(_))
+        ));
+        assert_eq!(reason("[]"), "response meta must be an object");
+        assert_eq!(reason("null"), "response meta must be an object");
+        assert_eq!(reason(r#""body""#), "response meta must be an object");
+    }
+
+    #[test]
+    fn rejects_invalid_status() {
+        assert_eq!(reason(r#"{"status":"200"}"#), r#"invalid status: "200""#);
+        assert_eq!(reason(r#"{"status":-1}"#), "invalid status: -1");
+        assert_eq!(reason(r#"{"status":200.5}"#), "invalid status: 200.5");
+        assert_eq!(reason(r#"{"status":70000}"#), "invalid status: 70000");
+        assert_eq!(reason(r#"{"status":99}"#), "invalid status: 99");
+        assert_eq!(reason(r#"{"status":1000}"#), "invalid status: 1000");
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        assert_eq!(reason(r#"{"headers":[]}"#), "headers must be an object");
+        assert_eq!(
+            reason(r#"{"headers":"X-A: 1"}"#),
+            "headers must be an object"
+        );
+        assert_eq!(
+            reason(r#"{"headers":{"X A":"1"}}"#),
+            "invalid header name: X A"
+        );
+        assert_eq!(reason(r#"{"headers":{"":"1"}}"#), "invalid header name: ");
+        assert_eq!(
+            reason(r#"{"headers":{"X-A":"1\r\nX-B: 2"}}"#),
+            "invalid value of header x-a"
+        );
+        assert_eq!(
+            reason(r#"{"headers":{"X-A":{"value":"1"}}}"#),
+            "invalid value of header x-a"
+        );
+        assert_eq!(
+            reason(r#"{"headers":{"X-A":["1",null]}}"#),
+            "invalid value of header x-a"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_head_and_chunks() {
+        assert_eq!(reason(r#"{"head":["<a>"]}"#), "head must be a string");
+        assert_eq!(
+            reason(r#"{"chunks":"main"}"#),
+            "chunks must be an array of strings"
+        );
+        assert_eq!(
+            reason(r#"{"chunks":["main",1]}"#),
+            "chunks must be an array of strings"
+        );
+    }
+}
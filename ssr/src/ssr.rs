@@ -1,16 +1,31 @@
-use std::{fs, net::Shutdown, path::PathBuf, sync::Arc};
+use std::{
+    cmp, fs,
+    future::Future,
+    path::PathBuf,
+    process::ExitStatus,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use http::Uri;
+use bytes::Bytes;
+use futures_util::stream::{self, BoxStream, StreamExt};
 use serde::Serialize;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::time;
 use uuid::Uuid;
 
 use crate::{
+    breaker::{Attempt, CircuitBreakerConfig},
+    cache::{Cache, CacheConfig, Coalesced, Flight, Lookup},
+    connection::Exchange,
     error::{InitializationError, RenderingError},
-    worker::{Port, Worker},
+    fallback::{Fallback, Shell},
+    health::Health,
+    manifest::{AssetManifest, Manifest},
+    pool::{Lease, Pool},
+    protocol::{self, Frame, RenderFrame},
+    request::{HeaderFilter, RenderRequest},
+    response::{self, Head, RenderResponse, RenderStream},
+    worker::{Worker, WorkerConfig},
 };
 
 /// Enum that instructs which JS renderer to use when [`ssr.render`](Ssr::render) gets called.
@@ -25,6 +40,60 @@ pub enum JsRenderer {
     },
 }
 
+/// Per-request rendering options, passed to [`ssr.render`](Ssr::render). A plain
+/// [`JsRenderer`](JsRenderer) can be passed instead when the defaults are good enough.
+///
+/// # Example
+///
+/// ```rust
+/// let options = RenderOptions {
+///     timeout: Some(Duration::from_millis(500)),
+///     ..RenderOptions::default()
+/// };
+/// ssr.render(uri, &data, options).await
+/// ```
+pub struct RenderOptions {
+    /// JS renderer to use for this request.
+    pub js_renderer: JsRenderer,
+    /// Overrides [`SsrConfig::render_timeout`](SsrConfig::render_timeout) for this request.
+    pub timeout: Option<Duration>,
+    /// A point in time by which rendering must complete, e.g. derived from a deadline of the
+    /// incoming request. If both timeout and deadline are set, whichever comes first applies.
+    pub deadline: Option<Instant>,
+    /// Whether the response can be served from and stored in the cache, if it's enabled via
+    /// [`SsrConfig::cache`](SsrConfig::cache). Opt out for responses that depend on anything
+    /// not passed to JS renderer, e.g. the current time.
+    pub cache: bool,
+    /// A CSP nonce of the response. It's injected into the fallback shell served instead of the
+    /// response if rendering fails, see [`SsrConfig::fallback`](SsrConfig::fallback).
+    pub nonce: Option<String>,
+}
+
+impl RenderOptions {
+    /// Creates options with the given JS renderer and default timeouts.
+    pub fn new(js_renderer: JsRenderer) -> Self {
+        Self {
+            js_renderer,
+            timeout: None,
+            deadline: None,
+            cache: true,
+            nonce: None,
+        }
+    }
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self::new(JsRenderer::Global)
+    }
+}
+
+impl From<JsRenderer> for RenderOptions {
+    fn from(js_renderer: JsRenderer) -> Self {
+        Self::new(js_renderer)
+    }
+}
+
 /// Sets log verbosity of Node.js worker.
 pub enum JsWorkerLog {
     /// Logs only warnings and errors.
@@ -42,10 +111,128 @@ impl JsWorkerLog {
     }
 }
 
+/// Defines how Rust side communicates with Node.js workers.
+#[derive(Clone, Debug, Default)]
+pub enum Transport {
+    /// Workers listen on TCP ports of `127.0.0.1` starting from
+    /// [`SsrConfig::port`](SsrConfig::port).
+    #[default]
+    Tcp,
+    /// Workers listen on Unix domain sockets created in the given directory. Sockets are
+    /// accessible only by the user the process is running as. It's faster than TCP loopback and
+    /// doesn't expose workers to other local users, but the directory must be writable and
+    /// shouldn't be shared with untrusted processes. Falls back to [`Tcp`](Transport::Tcp) on
+    /// platforms without Unix domain sockets.
+    UnixSocket {
+        /// A directory where sockets of workers are created.
+        dir: PathBuf,
+    },
+}
+
+/// Defines what happens when a Node.js worker process exits unexpectedly.
+#[derive(Clone, Copy, Debug)]
+pub enum RestartPolicy {
+    /// Worker stays dead and all rendering requests dispatched to it fail with
+    /// [`RenderingError::WorkerIsUnavailable`](RenderingError::WorkerIsUnavailable).
+    Never,
+    /// Worker gets respawned with the same arguments. If respawning fails, the delay between
+    /// attempts doubles, starting from `initial_delay` up to `max_delay`.
+    Backoff {
+        /// A delay before the first restart attempt.
+        initial_delay: Duration,
+        /// An upper bound of a delay between restart attempts.
+        max_delay: Duration,
+    },
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy::Backoff {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Bounds the number of renders a worker handles at once. Renders exceeding the limit wait in a
+/// queue until a worker frees up. Once the queue is full, or a render waits longer than
+/// `queue_timeout`, it fails right away with
+/// [`RenderingError::Overloaded`](RenderingError::Overloaded), so the page can be rendered on the
+/// client instead of waiting for an overloaded worker.
+#[derive(Clone, Copy, Debug)]
+pub struct ConcurrencyLimit {
+    /// Max number of renders in flight per worker.
+    pub max_in_flight: usize,
+    /// Max number of renders waiting for a free worker across the pool.
+    pub max_queued: usize,
+    /// How long a render can wait for a free worker.
+    pub queue_timeout: Duration,
+}
+
+impl Default for ConcurrencyLimit {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 64,
+            max_queued: 256,
+            queue_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// An event in the lifecycle of a Node.js worker, reported to
+/// [`SsrConfig::on_worker_event`](SsrConfig::on_worker_event) hook.
+#[derive(Debug)]
+pub enum WorkerEvent {
+    /// Worker process exited.
+    Exited {
+        /// An index of the worker in the pool.
+        worker: usize,
+        /// Pid of the exited process.
+        pid: u32,
+        /// Exit status of the process, if it could be retrieved.
+        status: Option<ExitStatus>,
+    },
+    /// Worker process was respawned.
+    Restarted {
+        /// An index of the worker in the pool.
+        worker: usize,
+        /// Pid of the new process.
+        pid: u32,
+        /// Total number of restarts of this worker.
+        restarts: usize,
+    },
+    /// An attempt to respawn worker process failed.
+    RestartFailed {
+        /// An index of the worker in the pool.
+        worker: usize,
+        /// A number of the failed attempt.
+        attempt: u32,
+        /// An error returned on spawning the process or waiting for its readiness.
+        error: InitializationError,
+    },
+}
+
+/// A callback that gets called on each [`WorkerEvent`](WorkerEvent).
+pub type WorkerEventHook = Arc<dyn Fn(&WorkerEvent) + Send + Sync>;
+
 /// A global configuration for [`Ssr`](Ssr) instance.
 pub struct SsrConfig {
-    /// A port that Node.js worker will be listening on.
+    /// A port that Node.js worker will be listening on. When a pool consists of multiple workers,
+    /// each subsequent worker listens on the next port, i.e. `port`, `port + 1`, ..., `port +
+    /// workers - 1`. If it's `0`, each worker binds a free ephemeral port. Ignored when workers
+    /// listen on Unix domain sockets.
     pub port: u16,
+    /// Defines whether workers listen on TCP ports or Unix domain sockets.
+    pub transport: Transport,
+    /// A number of Node.js workers in the pool. Rendering requests are dispatched to the worker
+    /// with the least number of in-flight renders.
+    pub workers: usize,
+    /// A number of persistent connections to each worker. Concurrent rendering requests are
+    /// multiplexed over these connections. Pings of the worker go over a separate connection.
+    pub connections_per_worker: usize,
+    /// Bounds the number of renders in flight per worker and queues the rest. `None` (default)
+    /// means renders are sent to workers right away regardless of their load.
+    pub concurrency_limit: Option<ConcurrencyLimit>,
     /// Path to Node.js worker installed from `npm`. It should be relative to the
     /// [`std::env::current_dir`](std::env::current_dir).
     pub js_worker: PathBuf,
@@ -58,18 +245,96 @@ pub struct SsrConfig {
     /// since JS module has to be required during a request as opposed to requiring it once on
     /// application startup.
     pub global_js_renderer: Option<PathBuf>,
+    /// Whether stack frames of JS errors get mapped to original sources using source maps. A
+    /// source map is looked up next to the file of a frame, e.g. `bundle.js.map` for `bundle.js`.
+    /// Source maps are loaded on the first error in a file and cached for the lifetime of the
+    /// worker.
+    pub source_maps: bool,
+    /// How long to wait for a worker to report readiness on startup or restart. If the worker
+    /// doesn't make it in time, [`Ssr::new`](Ssr::new) fails.
+    pub startup_timeout: Duration,
+    /// A default time limit for a single rendering request. Rendering is aborted with
+    /// [`RenderingError::Timeout`](RenderingError::Timeout) once it's exceeded. `None` means
+    /// rendering can take forever, which is not recommended since a JS renderer stuck in an
+    /// infinite loop or a never-resolving promise would hang a request handler.
+    pub render_timeout: Option<Duration>,
+    /// Defines which headers of an incoming request are forwarded to JS renderer when a request
+    /// is rendered from [`Request`](http::Request) or [`Parts`](http::request::Parts). By default,
+    /// all headers except for `Authorization` and `Proxy-Authorization` are forwarded.
+    pub forwarded_headers: HeaderFilter,
+    /// Defines whether and how crashed workers get restarted.
+    pub restart_policy: RestartPolicy,
+    /// How long [`ssr.health`](Ssr::health) waits for a worker to respond to a ping before
+    /// reporting it as [`Degraded`](crate::WorkerState::Degraded). A worker is also pinged once a
+    /// render times out, and gets killed and restarted if it doesn't respond in time, e.g. because
+    /// JS renderer is stuck in a synchronous loop.
+    pub ping_timeout: Duration,
+    /// A callback that gets notified when a worker exits or gets restarted. Events are logged
+    /// regardless of this hook, so it is useful mostly for collecting metrics or alerting.
+    pub on_worker_event: Option<WorkerEventHook>,
+    /// Enables a circuit breaker in front of each worker, which stops sending renders to a worker
+    /// once most of them fail, e.g. after a bad deploy of the renderer bundle. `None` (default)
+    /// means renders are sent to workers regardless of their failures.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Enables an in-memory cache of responses rendered via [`ssr.render`](Ssr::render) and
+    /// [`ssr.render_response`](Ssr::render_response). Responses are cached by path and query of
+    /// the request, JS renderer, forwarded method, headers and cookies, and data. Only successful
+    /// responses without `Cache-Control: no-store` or `private` and without `Set-Cookie` are
+    /// cached. Disabled by default.
+    pub cache: Option<CacheConfig>,
+    /// A static HTML shell served when rendering fails due to a timeout, an overload, a crashed
+    /// worker or a JS exception, so users get a page rendered on the client instead of an error.
+    /// The failure is logged. `None` (default) means rendering errors are returned as is.
+    pub fallback: Option<Fallback>,
+    /// An asset manifest of the bundler, which is passed to JS renderer, so it can report chunks
+    /// used by the page. Preloads of these chunks are added to head tags and `Link` headers of
+    /// the response. `None` (default) means no manifest is loaded.
+    pub asset_manifest: Option<AssetManifest>,
 }
 
-/// The main struct of the crate that manages Node.js process and handles rendering.
+impl Default for SsrConfig {
+    fn default() -> Self {
+        Self {
+            port: 9000,
+            transport: Transport::default(),
+            workers: 1,
+            connections_per_worker: 2,
+            concurrency_limit: None,
+            js_worker: PathBuf::from("./node_modules/ssr-rs/worker.js"),
+            js_worker_log: JsWorkerLog::Minimal,
+            global_js_renderer: None,
+            source_maps: true,
+            startup_timeout: Duration::from_secs(10),
+            render_timeout: Some(Duration::from_secs(30)),
+            forwarded_headers: HeaderFilter::default(),
+            restart_policy: RestartPolicy::default(),
+            ping_timeout: Duration::from_secs(1),
+            on_worker_event: None,
+            circuit_breaker: None,
+            cache: None,
+            fallback: None,
+            asset_manifest: None,
+        }
+    }
+}
+
+/// The main struct of the crate that manages Node.js processes and handles rendering.
 #[derive(Clone)]
 pub struct Ssr {
-    worker: Arc<Worker>,
+    pool: Arc<Pool>,
     js_worker: PathBuf,
     global_js_renderer: Option<PathBuf>,
+    render_timeout: Option<Duration>,
+    forwarded_headers: HeaderFilter,
+    cache: Option<Arc<Cache>>,
+    fallback: Option<Shell>,
+    manifest: Option<Arc<Manifest>>,
+    ping_timeout: Duration,
 }
 
 impl Ssr {
-    /// Creates an [`Ssr`](Ssr) instance.
+    /// Creates an [`Ssr`](Ssr) instance. Resolves once all workers in the pool are ready to
+    /// accept rendering requests.
     ///
     /// # Example
     ///
@@ -78,14 +343,15 @@ impl Ssr {
     ///   Ssr::new(
     ///     SsrConfig {
     ///       port: 9000,
+    ///       workers: 4,
     ///       js_worker: PathBuf::from("./node_modules/ssr-rs/worker.js"),
     ///       js_worker_log: JsWorkerLog::Verbose,
     ///       global_js_renderer: Some(PathBuf::from("./js/ssr.js")),
+    ///       ..SsrConfig::default()
     ///     }
     ///   );
     /// ```
     pub async fn new(cfg: SsrConfig) -> Result<Self, InitializationError> {
-        let port = Port::new(cfg.port);
         let js_worker = match fs::canonicalize(cfg.js_worker) {
             Ok(path) => path,
             Err(err) => return Err(InitializationError::InvalidJsWorkerPath(err)),
@@ -97,17 +363,55 @@ impl Ssr {
             },
             None => None,
         };
-        let worker =
-            Worker::new(&port, &js_worker, &cfg.js_worker_log, &global_js_renderer).await?;
+        let fallback = match cfg.fallback {
+            Some(fallback) => Some(Shell::load(fallback)?),
+            None => None,
+        };
+        let manifest = match cfg.asset_manifest {
+            Some(manifest) => Some(Arc::new(Manifest::load(manifest)?)),
+            None => None,
+        };
+        let pool = Pool::new(
+            cfg.port,
+            cfg.transport,
+            cfg.workers,
+            cfg.concurrency_limit,
+            WorkerConfig {
+                js_worker: js_worker.clone(),
+                js_worker_log: cfg.js_worker_log,
+                global_js_renderer: global_js_renderer.clone(),
+                asset_manifest: manifest.as_ref().map(|manifest| manifest.path.clone()),
+                source_maps: cfg.source_maps,
+                startup_timeout: cfg.startup_timeout,
+                ping_timeout: cfg.ping_timeout,
+                connections: cfg.connections_per_worker,
+                restart_policy: cfg.restart_policy,
+                on_worker_event: cfg.on_worker_event,
+                circuit_breaker: cfg.circuit_breaker,
+            },
+        )
+        .await?;
         Ok(Self {
-            worker: Arc::new(worker),
+            pool: Arc::new(pool),
             js_worker,
             global_js_renderer,
+            render_timeout: cfg.render_timeout,
+            forwarded_headers: cfg.forwarded_headers,
+            cache: cfg.cache.map(|cfg| Arc::new(Cache::new(cfg))),
+            fallback,
+            manifest,
+            ping_timeout: cfg.ping_timeout,
         })
     }
 
     /// Renders a response to an incoming request using Node.js worker.
     ///
+    /// The last argument is either a [`JsRenderer`](JsRenderer) or [`RenderOptions`](RenderOptions)
+    /// when the defaults, such as a render timeout, must be overridden for this request.
+    ///
+    /// Only a body of the rendered response is returned. If JS renderer reports status code,
+    /// headers or head tags, use [`render_response`](Ssr::render_response) instead.
+    ///
     /// # Example
     ///
     /// ```rust
@@ -120,133 +424,538 @@ impl Ssr {
     ///         HttpResponse::InternalServerError().finish()
     ///     }
     /// }
-    pub async fn render<D: Serialize>(
+    /// ```
+    pub async fn render<'r, R, D, O>(
         &self,
-        uri: &Uri,
+        req: R,
         data: &D,
-        js_renderer: JsRenderer,
-    ) -> Result<String, RenderingError> {
-        let request_id = Uuid::new_v4();
+        options: O,
+    ) -> Result<String, RenderingError>
+    where
+        R: Into<RenderRequest<'r>>,
+        D: Serialize,
+        O: Into<RenderOptions>,
+    {
+        self.render_response(req, data, options)
+            .await
+            .map(|res| res.body)
+    }
 
-        trace!("Starting request {}", request_id);
+    /// Renders a structured response to an incoming request using Node.js worker. In addition to
+    /// a body, it contains status code, headers and head tags reported by JS renderer. See
+    /// [`RenderResponse`](RenderResponse) for details.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let uri = req.uri();
+    /// let data = db::get_data();
+    /// match ssr.render_response(uri, &data, JsRenderer::Global).await {
+    ///     Ok(res) => {
+    ///         let mut http_res = HttpResponse::build(res.status);
+    ///         for (name, value) in res.headers.iter() {
+    ///             http_res.header(name, value.clone());
+    ///         }
+    ///         http_res.body(res.body)
+    ///     }
+    ///     Err(error) => {
+    ///         error!("Error: {}", error);
+    ///         HttpResponse::InternalServerError().finish()
+    ///     }
+    /// }
+    /// ```
+    pub async fn render_response<'r, R, D, O>(
+        &self,
+        req: R,
+        data: &D,
+        options: O,
+    ) -> Result<RenderResponse, RenderingError>
+    where
+        R: Into<RenderRequest<'r>>,
+        D: Serialize,
+        O: Into<RenderOptions>,
+    {
+        let options = options.into();
+        let input = self.render_input(req.into(), data, &options.js_renderer)?;
+        let deadline = Deadline::new(self.timeout(&options));
+        let res = match &self.cache {
+            Some(cache) if options.cache => {
+                let timeout = options.timeout.or(self.render_timeout);
+                self.render_cached(cache, &input, deadline, timeout).await
+            }
+            _ => self.render_uncached(&input.frame, deadline).await,
+        };
+        match res {
+            Ok(res) => Ok(res),
+            Err(err) => self.fall_back(err, &input.url, data, &options),
+        }
+    }
 
-        let worker = &self.worker;
+    /// Renders a response to an incoming request using Node.js worker and streams its body as
+    /// it's being rendered. Resolves as soon as JS renderer reports status code, headers and head
+    /// tags, so the response can be sent to a client before rendering is complete. See
+    /// [`RenderStream`](RenderStream) for details.
+    ///
+    /// Render timeout applies to the whole rendering, including streaming of the body.
+    /// Streamed responses bypass the cache configured via [`SsrConfig::cache`](SsrConfig::cache).
+    /// The body should be consumed as it arrives: once a few megabytes of it pile up, rendering
+    /// is cancelled and the stream fails with
+    /// [`RenderingError::BacklogExceeded`](RenderingError::BacklogExceeded).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let uri = req.uri();
+    /// let data = db::get_data();
+    /// match ssr.render_stream(uri, &data, JsRenderer::Global).await {
+    ///     Ok(res) => HttpResponse::build(res.status).streaming(res.map_err(|error| {
+    ///         error!("Error: {}", error);
+    ///         actix_web::error::ErrorInternalServerError(error)
+    ///     })),
+    ///     Err(error) => {
+    ///         error!("Error: {}", error);
+    ///         HttpResponse::InternalServerError().finish()
+    ///     }
+    /// }
+    /// ```
+    pub async fn render_stream<'r, R, D, O>(
+        &self,
+        req: R,
+        data: &D,
+        options: O,
+    ) -> Result<RenderStream, RenderingError>
+    where
+        R: Into<RenderRequest<'r>>,
+        D: Serialize,
+        O: Into<RenderOptions>,
+    {
+        let options = options.into();
+        let input = self.render_input(req.into(), data, &options.js_renderer)?;
+        let deadline = Deadline::new(self.timeout(&options));
+        match self.stream(&input.frame, deadline).await {
+            Ok(res) => Ok(res),
+            Err(err) => self
+                .fall_back(err, &input.url, data, &options)
+                .map(RenderStream::from_response),
+        }
+    }
 
-        let mut stream = match worker.connect().await {
-            Ok(stream) => stream,
-            Err(err) => {
-                error!(
-                    "{worker}: Failed to connect: {err}",
-                    worker = worker.display_with_request_id(&request_id),
-                    err = err
-                );
-                return Err(RenderingError::ConnectionError(err));
+    /// Shuts down all workers gracefully. New rendering requests are rejected with
+    /// [`RenderingError::ShuttingDown`](RenderingError::ShuttingDown) right away, while in-flight
+    /// ones are given `timeout` to complete. Then workers are asked to exit via `SIGTERM` and get
+    /// killed if they are still running once the timeout is exceeded.
+    ///
+    /// Shutdown affects all clones of this [`Ssr`](Ssr) instance. Workers can't be restarted
+    /// afterwards, a new instance must be created instead.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// server.await?;
+    /// ssr.shutdown(Duration::from_secs(10)).await;
+    /// ```
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.pool.shutdown(time::Instant::now() + timeout).await
+    }
+
+    /// Reports health of workers, pinging each of them with a lightweight message instead of
+    /// a render. Wire it into a readiness probe to take an instance out of rotation when
+    /// rendering is not possible.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// async fn healthz(ssr: Data<Ssr>) -> HttpResponse {
+    ///     let health = ssr.health().await;
+    ///     if health.is_ready() {
+    ///         HttpResponse::Ok().body(format!("{:?}", health))
+    ///     } else {
+    ///         HttpResponse::ServiceUnavailable().body(format!("{:?}", health))
+    ///     }
+    /// }
+    /// ```
+    pub async fn health(&self) -> Health {
+        self.pool.health(self.ping_timeout).await
+    }
+
+    /// Removes all responses from the cache. Does nothing if the cache is disabled.
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+    }
+
+    /// Removes cached responses of requests whose path and query match the predicate, e.g. once
+    /// the underlying content has changed. Does nothing if the cache is disabled.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// ssr.invalidate_cache(|url| url.starts_with("/posts/"));
+    /// ```
+    pub fn invalidate_cache<F>(&self, predicate: F)
+    where
+        F: Fn(&str) -> bool,
+    {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(predicate);
+        }
+    }
+
+    fn timeout(&self, options: &RenderOptions) -> Option<Duration> {
+        let timeout = options.timeout.or(self.render_timeout);
+        match options.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                Some(timeout.map_or(remaining, |timeout| cmp::min(timeout, remaining)))
             }
-        };
+            None => timeout,
+        }
+    }
 
-        let url = match uri.path_and_query() {
+    // Serializes rendering request. Its payload doesn't depend on the request id, so identical
+    // requests produce identical payloads, which makes the payload suitable as a cache key.
+    fn render_input<D: Serialize>(
+        &self,
+        req: RenderRequest<'_>,
+        data: &D,
+        js_renderer: &JsRenderer,
+    ) -> Result<RenderInput, RenderingError> {
+        let url = match req.uri.path_and_query() {
             Some(url) => url,
-            None => {
-                Self::finalize_rendering_session(&worker, &stream, &request_id);
-                return Err(RenderingError::InvalidUri);
-            }
+            None => return Err(RenderingError::InvalidUri),
         };
 
         let request_renderer = match (&self.global_js_renderer, js_renderer) {
             (Some(_), JsRenderer::Global) => None,
             (_, JsRenderer::PerRequest { path }) => Some(path),
-            (None, JsRenderer::Global) => {
-                Self::finalize_rendering_session(&worker, &stream, &request_id);
-                return Err(RenderingError::GlobalRendererNotProvided);
-            }
+            (None, JsRenderer::Global) => return Err(RenderingError::GlobalRendererNotProvided),
         };
 
         let meta = json!({
-          "requestId": request_id,
           "requestRenderer": request_renderer,
           "url": json!({"path": url.path(), "query": url.query()}),
+          "method": req.method_json(),
+          "headers": req.headers_json(&self.forwarded_headers),
+          "cookies": req.cookies_json(&self.forwarded_headers),
         });
-        let meta_bytes = match serde_json::to_vec(&meta) {
-            Ok(bytes) => bytes,
-            Err(err) => {
-                Self::finalize_rendering_session(&worker, &stream, &request_id);
-                return Err(RenderingError::UrlSerializationError(err));
+        Ok(RenderInput {
+            url: url.as_str().to_string(),
+            frame: protocol::encode_render(Uuid::new_v4(), &meta, data)?,
+        })
+    }
+
+    // Serves a response from the cache if possible. Otherwise, renders it unless the same request
+    // is being rendered already, in which case the response of that render is shared.
+    async fn render_cached(
+        &self,
+        cache: &Arc<Cache>,
+        input: &RenderInput,
+        deadline: Deadline,
+        timeout: Option<Duration>,
+    ) -> Result<RenderResponse, RenderingError> {
+        let key = cache.key(input.frame.payload());
+        loop {
+            match cache.lookup(&key) {
+                Lookup::Fresh(res) => {
+                    trace!("Serving {url} from cache", url = input.url);
+                    return Ok(res);
+                }
+                Lookup::Stale(res, flight) => {
+                    trace!("Serving stale {url} from cache", url = input.url);
+                    if let Some(flight) = flight {
+                        self.revalidate(flight, input.clone(), timeout);
+                    }
+                    return Ok(res);
+                }
+                Lookup::Pending(pending) => {
+                    trace!("Waiting for {url} being rendered", url = input.url);
+                    let waiting = async { Ok(pending.wait().await) };
+                    match deadline.run(waiting).await? {
+                        Coalesced::Done(res) => return res,
+                        Coalesced::Private => {
+                            return self.render_uncached(&input.frame, deadline).await
+                        }
+                        Coalesced::Abandoned => {}
+                    }
+                }
+                Lookup::Miss(flight) => {
+                    let res = self.render_uncached(&input.frame, deadline).await;
+                    return flight.complete(input.url.clone(), res);
+                }
             }
+        }
+    }
+
+    // Refreshes a stale response in the background. The refresh isn't bound by the deadline of
+    // the request which triggered it, only by the render timeout.
+    fn revalidate(&self, flight: Flight, input: RenderInput, timeout: Option<Duration>) {
+        trace!("Revalidating {url}", url = input.url);
+        let ssr = self.clone();
+        tokio::spawn(async move {
+            let res = ssr
+                .render_uncached(&input.frame, Deadline::new(timeout))
+                .await;
+            flight.complete(input.url, res).ok();
+        });
+    }
+
+    // Replaces the error with the fallback shell, if it's configured and applies to the error.
+    fn fall_back<D: Serialize>(
+        &self,
+        err: RenderingError,
+        url: &str,
+        data: &D,
+        options: &RenderOptions,
+    ) -> Result<RenderResponse, RenderingError> {
+        let shell = match &self.fallback {
+            Some(shell) if Shell::applies_to(&err) => shell,
+            _ => return Err(err),
         };
-        let data = match serde_json::to_string(&data) {
-            Ok(data) => data,
+        match shell.render(data, options.nonce.as_deref()) {
+            Ok(res) => {
+                error!(
+                    "Failed to render {url}, serving fallback shell: {err}",
+                    url = url,
+                    err = err
+                );
+                Ok(res)
+            }
+            Err(_) => Err(err),
+        }
+    }
+
+    async fn render_uncached(
+        &self,
+        input: &RenderFrame,
+        deadline: Deadline,
+    ) -> Result<RenderResponse, RenderingError> {
+        self.stream(input, deadline).await?.into_response().await
+    }
+
+    async fn stream(
+        &self,
+        input: &RenderFrame,
+        deadline: Deadline,
+    ) -> Result<RenderStream, RenderingError> {
+        let request_id = input.id();
+
+        trace!("Starting request {}", request_id);
+
+        if self.pool.is_closing() {
+            return Err(RenderingError::ShuttingDown);
+        }
+
+        let lease = match deadline.run(self.pool.checkout()).await {
+            Ok(lease) => lease,
             Err(err) => {
-                Self::finalize_rendering_session(&worker, &stream, &request_id);
-                return Err(RenderingError::DataSerializationError(err));
+                warn!("Request {} is rejected: {}", request_id, err);
+                return Err(err);
             }
         };
-        let data_bytes = match crate::json::to_vec(&data) {
-            Ok(bytes) => bytes,
+
+        let worker = lease.handle();
+        if !worker.is_ready() {
+            error!(
+                "{worker}: Worker is not ready",
+                worker = worker.display_with_request_id(&request_id),
+            );
+            return Err(RenderingError::WorkerIsUnavailable);
+        }
+
+        let attempt = match worker.attempt() {
+            Ok(attempt) => attempt,
             Err(err) => {
-                Self::finalize_rendering_session(&worker, &stream, &request_id);
-                return Err(RenderingError::DataSerializationError(err));
+                debug!(
+                    "{worker}: Circuit is open, rejecting request",
+                    worker = worker.display_with_request_id(&request_id),
+                );
+                return Err(err);
             }
         };
-        let meta_len = meta_bytes.len() as u32;
-        let data_len = data_bytes.len() as u32;
-        let meta_len_bytes = meta_len.to_be_bytes();
-        let data_len_bytes = data_len.to_be_bytes();
-        let mut input = meta_len_bytes.to_vec();
-        input.extend_from_slice(&data_len_bytes);
-        input.extend(meta_bytes);
-        input.extend(data_bytes);
 
-        let mut res = String::new();
+        let rendering = self.start_rendering(lease, request_id, input);
 
-        trace!(
-            "{worker}: Writing input to socket",
-            worker = worker.display_with_request_id(&request_id),
-        );
+        // If the deadline is exceeded, the rendering future gets dropped along with the
+        // exchange, which cancels rendering in the js worker.
+        let (exchange, mut head) = match deadline.run(rendering).await {
+            Ok(res) => res,
+            Err(err) => {
+                if let RenderingError::Timeout(timeout) = err {
+                    error!(
+                        "{worker}: Rendering timed out after {timeout}ms",
+                        worker = worker.display_with_request_id(&request_id),
+                        timeout = timeout.as_millis()
+                    );
+                    worker.clone().probe();
+                }
+                if let Some(attempt) = attempt {
+                    attempt.fail(&worker, &err);
+                }
+                return Err(err);
+            }
+        };
+
+        if let Some(manifest) = &self.manifest {
+            manifest.preload(&mut head);
+        }
 
-        if let Err(err) = stream.write_all(input.as_slice()).await {
-            Self::finalize_rendering_session(&worker, &stream, &request_id);
-            return Err(RenderingError::RenderRequestError(err));
+        let body = Body {
+            worker,
+            exchange,
+            request_id,
+            deadline,
+            attempt,
         };
 
-        trace!(
-            "{worker}: Input written to socket",
-            worker = worker.display_with_request_id(&request_id),
-        );
+        Ok(RenderStream::new(head, body.into_stream()))
+    }
 
-        if let Err(err) = stream.read_to_string(&mut res).await {
-            Self::finalize_rendering_session(&worker, &stream, &request_id);
-            return Err(RenderingError::RenderResponseError(err));
+    // Sends rendering request to the worker and waits for the response meta. The lease is held
+    // by the exchange until the worker is done with the request.
+    async fn start_rendering(
+        &self,
+        lease: Lease,
+        request_id: Uuid,
+        input: &RenderFrame,
+    ) -> Result<(Exchange, Head), RenderingError> {
+        let worker = lease.handle();
+        let frame = input.frame();
+
+        let connection = match worker.connection().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                error!(
+                    "{worker}: Failed to connect: {err}",
+                    worker = worker.display_with_request_id(&request_id),
+                    err = err
+                );
+                return Err(RenderingError::ConnectionError(err));
+            }
         };
 
         trace!(
-            "{worker}: Output written to result buffer",
+            "{worker}: Sending rendering request",
             worker = worker.display_with_request_id(&request_id),
         );
 
-        // No need to shutdown connection as it's already closed by the js worker
-        if res.starts_with("ERROR:") {
-            trace!(
-                "{worker}: Output is an error",
-                worker = worker.display_with_request_id(&request_id),
-            );
-            let stack = res.strip_prefix("ERROR:").unwrap();
-            Err(RenderingError::JsExceptionDuringRendering(stack.to_string()))
-        } else {
-            trace!(
-                "{worker}: Output is ok",
-                worker = worker.display_with_request_id(&request_id),
-            );
-            Ok(res)
+        // If anything goes wrong from now on, dropped exchange cancels rendering in the js worker
+        let mut exchange = connection.start(request_id, frame, Some(lease)).await?;
+
+        match exchange.next_frame().await? {
+            Frame::Head(meta) => {
+                let head = response::parse_head(&meta)?;
+                trace!(
+                    "{worker}: Received response meta",
+                    worker = worker.display_with_request_id(&request_id),
+                );
+                Ok((exchange, head))
+            }
+            Frame::Error(payload) => {
+                trace!(
+                    "{worker}: Output is an error",
+                    worker = worker.display_with_request_id(&request_id),
+                );
+                Err(response::parse_error(&payload))
+            }
+            Frame::Chunk(_) | Frame::End => Err(RenderingError::InvalidResponse(
+                "body received before response meta".to_string(),
+            )),
+            Frame::Pong => Err(RenderingError::InvalidResponse(
+                "pong received instead of response meta".to_string(),
+            )),
         }
     }
+}
 
-    fn finalize_rendering_session(worker: &Worker, connection: &TcpStream, request_id: &Uuid) {
-        if let Err(err) = connection.shutdown(Shutdown::Both) {
-            warn!(
-                "{worker}: Failed to shutdown connection to the js worker: {err}",
-                worker = worker.display_with_request_id(&request_id),
-                err = err
-            );
-        };
+// Serialized rendering request along with its path and query.
+#[derive(Clone)]
+struct RenderInput {
+    url: String,
+    frame: RenderFrame,
+}
+
+#[derive(Clone, Copy)]
+struct Deadline(Option<(time::Instant, Duration)>);
+
+impl Deadline {
+    fn new(timeout: Option<Duration>) -> Self {
+        Self(timeout.map(|timeout| (time::Instant::now() + timeout, timeout)))
+    }
+
+    async fn run<T, F>(self, fut: F) -> Result<T, RenderingError>
+    where
+        F: Future<Output = Result<T, RenderingError>>,
+    {
+        match self.0 {
+            None => fut.await,
+            Some((deadline, timeout)) => match time::timeout_at(deadline, fut).await {
+                Ok(res) => res,
+                Err(_) => Err(RenderingError::Timeout(timeout)),
+            },
+        }
+    }
+}
+
+// Reads body of the response from the worker.
+struct Body {
+    worker: Arc<Worker>,
+    exchange: Exchange,
+    request_id: Uuid,
+    deadline: Deadline,
+    attempt: Option<Attempt>,
+}
+
+impl Body {
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, RenderingError> {
+        let frame = self
+            .deadline
+            .run(self.exchange.next_frame())
+            .await?;
+        match frame {
+            Frame::Chunk(chunk) => Ok(Some(chunk)),
+            Frame::End => Ok(None),
+            Frame::Error(payload) => Err(response::parse_error(&payload)),
+            Frame::Head(_) => Err(RenderingError::InvalidResponse(
+                "response meta received twice".to_string(),
+            )),
+            Frame::Pong => Err(RenderingError::InvalidResponse(
+                "pong received instead of response body".to_string(),
+            )),
+        }
+    }
+
+    fn into_stream(self) -> BoxStream<'static, Result<Bytes, RenderingError>> {
+        stream::unfold(Some(self), |body| async move {
+            let mut body = body?;
+            match body.next_chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), Some(body))),
+                Ok(None) => {
+                    trace!(
+                        "{worker}: Output is ok",
+                        worker = body.worker.display_with_request_id(&body.request_id),
+                    );
+                    if let Some(attempt) = body.attempt.take() {
+                        attempt.succeed(&body.worker);
+                    }
+                    None
+                }
+                Err(err) => {
+                    error!(
+                        "{worker}: Failed to render body: {err}",
+                        worker = body.worker.display_with_request_id(&body.request_id),
+                        err = err
+                    );
+                    if let RenderingError::Timeout(_) = err {
+                        body.worker.clone().probe();
+                    }
+                    if let Some(attempt) = body.attempt.take() {
+                        attempt.fail(&body.worker, &err);
+                    }
+                    Some((Err(err), None))
+                }
+            }
+        })
+        .boxed()
     }
 }
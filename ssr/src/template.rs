@@ -0,0 +1,359 @@
+use std::{fs, path::Path, sync::Arc};
+
+use serde::Serialize;
+
+use crate::{
+    error::{InitializationError, RenderingError},
+    json,
+    response::RenderResponse,
+};
+
+/// Markers of slots in an [`HtmlTemplate`](HtmlTemplate). Each slot can appear in the template
+/// any number of times.
+#[derive(Clone, Debug)]
+pub struct TemplateSlots {
+    /// Replaced with [`RenderResponse::head`](crate::RenderResponse::head). Defaults to
+    /// `<!--ssr-head-->`.
+    pub head: String,
+    /// Replaced with [`RenderResponse::body`](crate::RenderResponse::body). Defaults to
+    /// `<!--ssr-body-->`. The template must contain this slot.
+    pub body: String,
+    /// Replaced with a `<script>` which assigns data of the request to [`global`](Self::global).
+    /// Defaults to `<!--ssr-hydration-->`.
+    pub data: String,
+    /// Replaced with a CSP nonce, e.g. in `<script nonce="%SSR_NONCE%">`, or with an empty
+    /// string if there's no nonce. Defaults to `%SSR_NONCE%`.
+    pub nonce: String,
+    /// An expression the hydration script assigns data of the request to. It's inserted into
+    /// the script as is. Defaults to `window.__SSR_DATA__`.
+    pub global: String,
+}
+
+impl Default for TemplateSlots {
+    fn default() -> Self {
+        Self {
+            head: "<!--ssr-head-->".to_string(),
+            body: "<!--ssr-body-->".to_string(),
+            data: "<!--ssr-hydration-->".to_string(),
+            nonce: "%SSR_NONCE%".to_string(),
+            global: "window.__SSR_DATA__".to_string(),
+        }
+    }
+}
+
+/// An HTML document, e.g. `index.html` produced by a bundler, which a rendered response is
+/// injected into. The template is split into slots once, so rendering a document is a
+/// concatenation of its parts. It's cheap to clone.
+///
+/// # Example
+///
+/// ```html
+/// <!DOCTYPE html>
+/// <html>
+///   <head>
+///     <!--ssr-head-->
+///     <script nonce="%SSR_NONCE%" src="/app.js" defer></script>
+///   </head>
+///   <body>
+///     <div id="app"><!--ssr-body--></div>
+///     <!--ssr-hydration-->
+///   </body>
+/// </html>
+/// ```
+///
+/// ```rust
+/// let template = HtmlTemplate::load("./dist/index.html")?;
+///
+/// let res = ssr.render_response(uri, &data, JsRenderer::Global).await?;
+/// let html = template.render(&res, &data, Some(&nonce))?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct HtmlTemplate(Arc<Template>);
+
+#[derive(Debug)]
+struct Template {
+    parts: Vec<Part>,
+    global: String,
+}
+
+#[derive(Debug)]
+enum Part {
+    Html(String),
+    Slot(Slot),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Slot {
+    Head,
+    Body,
+    Data,
+    Nonce,
+}
+
+impl Part {
+    fn content<'a>(&'a self, res: &'a RenderResponse, script: &'a str, nonce: &'a str) -> &'a str {
+        match self {
+            Part::Html(html) => html,
+            Part::Slot(Slot::Head) => &res.head,
+            Part::Slot(Slot::Body) => &res.body,
+            Part::Slot(Slot::Data) => script,
+            Part::Slot(Slot::Nonce) => nonce,
+        }
+    }
+}
+
+impl HtmlTemplate {
+    /// Reads the template from the file with the default slots.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, InitializationError> {
+        Self::load_with_slots(path, &TemplateSlots::default())
+    }
+
+    /// Reads the template from the file with the given slots.
+    pub fn load_with_slots(
+        path: impl AsRef<Path>,
+        slots: &TemplateSlots,
+    ) -> Result<Self, InitializationError> {
+        match fs::read_to_string(path) {
+            Ok(html) => Self::with_slots(&html, slots),
+            Err(err) => Err(InitializationError::InvalidHtmlTemplatePath(err)),
+        }
+    }
+
+    /// Creates a template from the HTML with the default slots, e.g. embedded via
+    /// `include_str!`.
+    pub fn new(html: &str) -> Result<Self, InitializationError> {
+        Self::with_slots(html, &TemplateSlots::default())
+    }
+
+    /// Creates a template from the HTML with the given slots.
+    pub fn with_slots(html: &str, slots: &TemplateSlots) -> Result<Self, InitializationError> {
+        let template = Self::parse(html, slots);
+        let has_body = template
+            .0
+            .parts
+            .iter()
+            .any(|part| matches!(part, Part::Slot(Slot::Body)));
+        if !has_body {
+            return Err(InitializationError::InvalidHtmlTemplate(format!(
+                "body slot {} is missing",
+                slots.body
+            )));
+        }
+        Ok(template)
+    }
+
+    // Splits the HTML into slots. Unlike a page template, a shell rendered on the client, e.g.
+    // the fallback one, doesn't need to have a body slot.
+    pub(crate) fn parse(html: &str, slots: &TemplateSlots) -> Self {
+        let markers = [
+            (&slots.head, Slot::Head),
+            (&slots.body, Slot::Body),
+            (&slots.data, Slot::Data),
+            (&slots.nonce, Slot::Nonce),
+        ];
+        let mut parts = Vec::new();
+        let mut rest = html;
+        loop {
+            // The earliest marker, the longest one if several start at the same position
+            let next = markers
+                .iter()
+                .filter(|(marker, _)| !marker.is_empty())
+                .filter_map(|(marker, slot)| {
+                    rest.find(marker.as_str())
+                        .map(|idx| (idx..idx + marker.len(), *slot))
+                })
+                .min_by_key(|(range, _)| (range.start, usize::MAX - range.end));
+            let (range, slot) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            if range.start > 0 {
+                parts.push(Part::Html(rest[..range.start].to_string()));
+            }
+            parts.push(Part::Slot(slot));
+            rest = &rest[range.end..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Html(rest.to_string()));
+        }
+        Self(Arc::new(Template {
+            parts,
+            global: slots.global.clone(),
+        }))
+    }
+
+    /// Injects head tags and body of the response, data of the request and a CSP nonce into
+    /// the template. Data is serialized with
+    /// [`Escaping::ScriptTag`](crate::json::Escaping::ScriptTag), the nonce is escaped for an
+    /// attribute value.
+    pub fn render<D>(
+        &self,
+        res: &RenderResponse,
+        data: &D,
+        nonce: Option<&str>,
+    ) -> Result<String, RenderingError>
+    where
+        D: Serialize + ?Sized,
+    {
+        let nonce = nonce.map(escape_attribute).unwrap_or_default();
+        let data = match json::to_script_safe_string(data) {
+            Ok(data) => data,
+            Err(err) => return Err(RenderingError::DataSerializationError(err)),
+        };
+        let global = &self.0.global;
+        let script = if nonce.is_empty() {
+            format!("<script>{} = {};</script>", global, data)
+        } else {
+            format!(
+                "<script nonce=\"{}\">{} = {};</script>",
+                nonce, global, data
+            )
+        };
+        let len = self
+            .0
+            .parts
+            .iter()
+            .map(|part| part.content(res, &script, &nonce).len())
+            .sum();
+        let mut html = String::with_capacity(len);
+        for part in self.0.parts.iter() {
+            html.push_str(part.content(res, &script, &nonce));
+        }
+        Ok(html)
+    }
+}
+
+pub(crate) fn escape_attribute(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for char in value.chars() {
+        match char {
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            char => escaped.push(char),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, StatusCode};
+
+    use super::*;
+
+    fn response() -> RenderResponse {
+        RenderResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            head: "<title>App</title>".to_string(),
+            body: "<p>Hello</p>".to_string(),
+        }
+    }
+
+    fn reason(html: &str, slots: &TemplateSlots) -> String {
+        match HtmlTemplate::with_slots(html, slots) {
+            Ok(_) => panic!("Template is created: {}", html),
+            Err(InitializationError::InvalidHtmlTemplate(reason)) => reason,
+            Err(err) => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn requires_body_slot() {
+        let slots = TemplateSlots::default();
+        assert_eq!(
+            reason("<head><!--ssr-head--></head>", &slots),
+            "body slot <!--ssr-body--> is missing"
+        );
+        let slots = TemplateSlots {
+            body: "{{body}}".to_string(),
+            ..TemplateSlots::default()
+        };
+        assert_eq!(
+            reason("<body><!--ssr-body--></body>", &slots),
+            "body slot {{body}} is missing"
+        );
+    }
+
+    #[test]
+    fn fills_every_occurrence_of_slots() {
+        let template = HtmlTemplate::new(
+            "<head><!--ssr-head--></head><body><!--ssr-body--><!--ssr-body--><!--ssr-head--></body>",
+        )
+        .unwrap();
+        let html = template.render(&response(), &(), None).unwrap();
+        assert_eq!(
+            html,
+            "<head><title>App</title></head><body><p>Hello</p><p>Hello</p><title>App</title></body>"
+        );
+    }
+
+    #[test]
+    fn prefers_longest_slot_at_same_position() {
+        let slots = TemplateSlots {
+            head: "<!--ssr-->".to_string(),
+            body: "<!--ssr--><!--body-->".to_string(),
+            ..TemplateSlots::default()
+        };
+        let template =
+            HtmlTemplate::with_slots("<!--ssr--><!--body--> <!--ssr-->", &slots).unwrap();
+        let html = template.render(&response(), &(), None).unwrap();
+        assert_eq!(html, "<p>Hello</p> <title>App</title>");
+    }
+
+    #[test]
+    fn renders_hydration_script_without_nonce() {
+        let template = HtmlTemplate::new(
+            "<script nonce=\"%SSR_NONCE%\"></script><!--ssr-body--><!--ssr-hydration-->",
+        )
+        .unwrap();
+        let html = template.render(&response(), &["</script>"], None).unwrap();
+        assert_eq!(
+            html,
+            "<script nonce=\"\"></script><p>Hello</p>\
+             <script>window.__SSR_DATA__ = [\"\\u003c/script\\u003e\"];</script>"
+        );
+    }
+
+    #[test]
+    fn renders_escaped_nonce() {
+        let template = HtmlTemplate::new(
+            "<script nonce=\"%SSR_NONCE%\"></script><!--ssr-body--><!--ssr-hydration-->",
+        )
+        .unwrap();
+        let html = template.render(&response(), &1, Some("a\"b<c>")).unwrap();
+        assert_eq!(
+            html,
+            "<script nonce=\"a&quot;b&lt;c&gt;\"></script><p>Hello</p>\
+             <script nonce=\"a&quot;b&lt;c&gt;\">window.__SSR_DATA__ = 1;</script>"
+        );
+    }
+
+    #[test]
+    fn assigns_data_to_custom_global() {
+        let slots = TemplateSlots {
+            global: "window.__APP_STATE__".to_string(),
+            ..TemplateSlots::default()
+        };
+        let template =
+            HtmlTemplate::with_slots("<!--ssr-body--><!--ssr-hydration-->", &slots).unwrap();
+        let html = template.render(&response(), &true, None).unwrap();
+        assert_eq!(
+            html,
+            "<p>Hello</p><script>window.__APP_STATE__ = true;</script>"
+        );
+    }
+
+    #[test]
+    fn escapes_attribute() {
+        assert_eq!(escape_attribute("plain"), "plain");
+        assert_eq!(
+            escape_attribute("\"'<a>&amp;"),
+            "&quot;&#39;&lt;a&gt;&amp;amp;"
+        );
+        assert_eq!(escape_attribute("ünï"), "ünï");
+    }
+}
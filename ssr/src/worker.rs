@@ -1,13 +1,39 @@
-use std::{fmt, io, net::SocketAddr, path::PathBuf, process::Stdio};
+use std::{
+    cmp,
+    convert::TryFrom,
+    fmt,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
+use serde_json::Value;
 use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
     net::TcpStream,
-    process::{Child, Command},
+    process::{Child, ChildStdout, Command},
+    sync::{mpsc, oneshot, Mutex, Notify},
     time,
 };
 use uuid::Uuid;
 
-use crate::{error::InitializationError, JsWorkerLog};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+use crate::{
+    breaker::{Attempt, Breaker, CircuitBreakerConfig},
+    connection::Connection,
+    error::{InitializationError, RenderingError},
+    health::{WorkerHealth, WorkerState},
+    protocol::{self, Frame},
+    JsWorkerLog, RestartPolicy, Transport, WorkerEvent, WorkerEventHook,
+};
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug)]
 pub(crate) struct Port(u16);
@@ -17,6 +43,11 @@ impl Port {
         Self(port)
     }
 
+    pub fn offset(&self, offset: usize) -> Option<Self> {
+        let offset = u16::try_from(offset).ok()?;
+        self.0.checked_add(offset).map(Self)
+    }
+
     pub fn to_string(&self) -> String {
         self.0.to_string()
     }
@@ -34,93 +65,704 @@ impl From<SocketAddr> for Port {
     }
 }
 
+// An address the worker listens on.
+#[derive(Clone, Debug)]
+pub(crate) enum Addr {
+    Tcp(Port),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Addr {
+    // Address of the worker with the given index in the pool. Returns `None` if the worker
+    // can't be assigned a port.
+    pub fn new(transport: &Transport, port: u16, idx: usize) -> Option<Self> {
+        match transport {
+            #[cfg(unix)]
+            Transport::UnixSocket { dir } => Some(Addr::Unix(dir.join(format!(
+                "ssr-{}-{}.sock",
+                std::process::id(),
+                idx
+            )))),
+            // Each worker binds its own ephemeral port
+            _ if port == 0 => Some(Addr::Tcp(Port::new(0))),
+            _ => Port::new(port).offset(idx).map(Addr::Tcp),
+        }
+    }
+
+    async fn connect(&self, worker: String) -> Result<Connection, io::Error> {
+        match self {
+            Addr::Tcp(port) => {
+                let stream = TcpStream::connect(port.to_socket_addr()).await?;
+                // Frames are written at once and shouldn't wait for more data
+                stream.set_nodelay(true)?;
+                Ok(Connection::new(stream, worker))
+            }
+            #[cfg(unix)]
+            Addr::Unix(path) => {
+                let stream = UnixStream::connect(path).await?;
+                Ok(Connection::new(stream, worker))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::Tcp(port) => write!(f, "port: {}", port.to_string()),
+            #[cfg(unix)]
+            Addr::Unix(path) => write!(f, "socket: {}", path.display()),
+        }
+    }
+}
+
 struct Process;
 
 impl Process {
+    const READY_MARKER: &'static [u8] = b"@@ssr-rs:ready";
+
     #[cfg(unix)]
     pub const SHELL: &'static str = "/bin/sh";
 
     #[cfg(windows)]
     pub const SHELL: &'static str = "cmd";
 
+    // `exec` replaces the shell with the node process, so the supervisor watches (and kills)
+    // the worker itself rather than the intermediate shell.
     #[cfg(unix)]
-    pub fn cmd(cmd: &str) -> Vec<&str> {
-        vec!["-c", &cmd]
+    pub fn cmd(cmd: &str) -> Vec<String> {
+        vec!["-c".to_string(), format!("exec {}", cmd)]
     }
 
     #[cfg(windows)]
-    pub fn cmd(cmd: &str) -> Vec<&str> {
-        vec!["/c", &cmd]
+    pub fn cmd(cmd: &str) -> Vec<String> {
+        vec!["/c".to_string(), cmd.to_string()]
     }
 
-    pub fn spawn(
-        port: &Port,
-        js_worker: &PathBuf,
-        js_worker_log: &JsWorkerLog,
-        global_js_renderer: &Option<PathBuf>,
-    ) -> Result<Child, io::Error> {
+    pub fn spawn(addr: &Addr, cfg: &WorkerConfig) -> Result<Child, io::Error> {
         let mut cmd = Command::new(Process::SHELL);
 
         cmd.kill_on_drop(true);
 
         cmd.args(Process::cmd(&format!(
             "node {}",
-            js_worker.as_path().display()
+            cfg.js_worker.as_path().display()
         )));
-        cmd.env("PORT", port.to_string());
-        cmd.env("LOG", js_worker_log.to_str());
+        match addr {
+            Addr::Tcp(port) => cmd.env("PORT", port.to_string()),
+            #[cfg(unix)]
+            Addr::Unix(path) => cmd.env("SOCKET", path.as_os_str()),
+        };
+        cmd.env("LOG", cfg.js_worker_log.to_str());
+
+        if cfg.source_maps {
+            cmd.env("SOURCE_MAPS", "true");
+        }
 
-        if let Some(global_renderer) = global_js_renderer {
+        if let Some(global_renderer) = &cfg.global_js_renderer {
             cmd.env(
                 "GLOBAL_RENDERER",
                 global_renderer.as_path().display().to_string(),
             );
         }
 
-        cmd.stdout(Stdio::inherit())
+        if let Some(asset_manifest) = &cfg.asset_manifest {
+            cmd.env(
+                "ASSET_MANIFEST",
+                asset_manifest.as_path().display().to_string(),
+            );
+        }
+
+        cmd.stdout(Stdio::piped())
             .stderr(Stdio::inherit())
             .spawn()
     }
+
+    // Spawns a worker process and waits until it reports readiness. Returns the process along with
+    // the address it actually listens on.
+    pub async fn start(
+        addr: &Addr,
+        cfg: &WorkerConfig,
+    ) -> Result<(Child, Addr), InitializationError> {
+        let mut process = Process::spawn(addr, cfg)?;
+        let stdout = process
+            .stdout
+            .take()
+            .expect("Stdout of js worker is not captured");
+        let (ready_tx, ready_rx) = oneshot::channel();
+
+        tokio::spawn(Process::read_stdout(stdout, ready_tx));
+
+        match time::timeout(
+            cfg.startup_timeout,
+            Process::wait_ready(&mut process, ready_rx, addr),
+        )
+        .await
+        {
+            Ok(Ok(addr)) => Ok((process, addr)),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(InitializationError::StartupTimeout(cfg.startup_timeout)),
+        }
+    }
+
+    async fn wait_ready(
+        process: &mut Child,
+        ready: oneshot::Receiver<Vec<u8>>,
+        addr: &Addr,
+    ) -> Result<Addr, InitializationError> {
+        match ready.await {
+            Ok(handshake) => Process::parse_handshake(&handshake, addr),
+            // Stdout got closed before the handshake, which means the process is exiting.
+            Err(_) => Err(InitializationError::WorkerExitedDuringStartup(
+                process.await.ok(),
+            )),
+        }
+    }
+
+    // Handshake contains a JSON with the port the worker listens on, which is the only way to
+    // find out which ephemeral port it bound.
+    fn parse_handshake(handshake: &[u8], addr: &Addr) -> Result<Addr, InitializationError> {
+        match addr {
+            Addr::Tcp(_) => {
+                let port = serde_json::from_slice::<Value>(handshake)
+                    .ok()
+                    .and_then(|handshake| handshake.get("port").and_then(Value::as_u64))
+                    .and_then(|port| u16::try_from(port).ok());
+                match port {
+                    Some(port) => Ok(Addr::Tcp(Port::new(port))),
+                    None => Err(InitializationError::InvalidHandshake(
+                        String::from_utf8_lossy(handshake).trim().to_string(),
+                    )),
+                }
+            }
+            #[cfg(unix)]
+            Addr::Unix(_) => Ok(addr.clone()),
+        }
+    }
+
+    // Worker writes a handshake line to stdout once it's ready to accept connections. All other
+    // output (e.g. `console.log` calls from JS renderers) is forwarded to stdout of the main
+    // process. Stdout must be drained for the whole lifetime of the process, otherwise the worker
+    // would get blocked on writing to a full pipe. Output is written via tokio stdout, so a slow
+    // stdout of the main process doesn't block a runtime thread.
+    async fn read_stdout(stdout: ChildStdout, ready: oneshot::Sender<Vec<u8>>) {
+        let mut ready = Some(ready);
+        let mut reader = BufReader::new(stdout);
+        let mut output = tokio::io::stdout();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line).await {
+                Ok(0) => break,
+                Ok(_) => {
+                    if line.starts_with(Process::READY_MARKER) {
+                        if let Some(ready) = ready.take() {
+                            ready.send(line[Process::READY_MARKER.len()..].to_vec()).ok();
+                        }
+                    } else if let Err(err) = Process::forward(&mut output, &line).await {
+                        warn!("[RS] Failed to forward output of js worker: {}", err);
+                    }
+                }
+                Err(err) => {
+                    warn!("[RS] Failed to read output of js worker: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn forward(output: &mut tokio::io::Stdout, line: &[u8]) -> io::Result<()> {
+        output.write_all(line).await?;
+        output.flush().await
+    }
+}
+
+pub(crate) struct WorkerConfig {
+    pub js_worker: PathBuf,
+    pub js_worker_log: JsWorkerLog,
+    pub global_js_renderer: Option<PathBuf>,
+    pub asset_manifest: Option<PathBuf>,
+    pub source_maps: bool,
+    pub startup_timeout: Duration,
+    pub ping_timeout: Duration,
+    pub connections: usize,
+    pub restart_policy: RestartPolicy,
+    pub on_worker_event: Option<WorkerEventHook>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+}
+
+impl WorkerConfig {
+    fn emit(&self, event: WorkerEvent) {
+        if let Some(hook) = &self.on_worker_event {
+            hook(&event);
+        }
+    }
+}
+
+// State shared between a worker handle and its supervisor.
+struct Status {
+    // Pid of the running process or `0` if the process is not running or is not ready yet.
+    pid: AtomicU32,
+    restarts: AtomicUsize,
+    // Address the current process listens on. Might change on restart if the port is ephemeral.
+    addr: RwLock<Addr>,
+    // When the current process was started.
+    started_at: RwLock<Instant>,
+    // Set once the supervisor gives up on the worker, so it's not going to be restarted.
+    dead: AtomicBool,
+}
+
+impl Status {
+    fn addr(&self) -> Addr {
+        self.addr.read().expect("Worker address lock is poisoned").clone()
+    }
+
+    fn set_addr(&self, addr: Addr) {
+        *self.addr.write().expect("Worker address lock is poisoned") = addr;
+    }
+
+    fn started_at(&self) -> Instant {
+        *self.started_at.read().expect("Worker start lock is poisoned")
+    }
+
+    fn set_started_at(&self, started_at: Instant) {
+        *self.started_at.write().expect("Worker start lock is poisoned") = started_at;
+    }
 }
 
 pub(crate) struct Worker {
-    addr: SocketAddr,
-    process: Child,
+    status: Arc<Status>,
+    pub(crate) in_flight: AtomicUsize,
+    // Notified once the last in-flight render completes.
+    idle: Notify,
+    connections: Vec<Mutex<Option<Arc<Connection>>>>,
+    next_connection: AtomicUsize,
+    // Pings go over a connection of their own, so they are not queued behind renders.
+    control: Mutex<Option<Arc<Connection>>>,
+    // Supervisor terminates the process gracefully once it receives a shutdown request and kills it
+    // right away once this sender is dropped.
+    supervisor: std::sync::Mutex<Option<oneshot::Sender<Shutdown>>>,
+    // Asks the supervisor to kill an unresponsive process with the given pid.
+    unresponsive: mpsc::UnboundedSender<u32>,
+    // Set while the process is being checked after a timed out render.
+    probing: AtomicBool,
+    ping_timeout: Duration,
+    breaker: Option<Arc<Breaker>>,
+}
+
+// A request to terminate the worker process gracefully.
+struct Shutdown {
+    // Process is killed if it doesn't exit by this time.
+    deadline: time::Instant,
+    done: oneshot::Sender<()>,
 }
 
 impl Worker {
     pub async fn new(
-        port: &Port,
-        js_worker: &PathBuf,
-        js_worker_log: &JsWorkerLog,
-        global_js_renderer: &Option<PathBuf>,
+        idx: usize,
+        addr: Addr,
+        cfg: Arc<WorkerConfig>,
     ) -> Result<Self, InitializationError> {
-        let process = Process::spawn(port, js_worker, js_worker_log, global_js_renderer)?;
+        let (process, actual_addr) = Process::start(&addr, &cfg).await?;
+        let status = Arc::new(Status {
+            pid: AtomicU32::new(process.id()),
+            restarts: AtomicUsize::new(0),
+            addr: RwLock::new(actual_addr),
+            started_at: RwLock::new(Instant::now()),
+            dead: AtomicBool::new(false),
+        });
+        let (supervisor, stop) = oneshot::channel();
+        let (unresponsive, kill) = mpsc::unbounded_channel();
+        let ping_timeout = cfg.ping_timeout;
+
+        let connections = (0..cfg.connections).map(|_| Mutex::new(None)).collect();
+        let breaker = cfg.circuit_breaker.map(|cfg| Arc::new(Breaker::new(cfg)));
+
+        let supervised = status.clone();
+        tokio::spawn(async move {
+            Self::supervise(idx, addr, process, cfg, supervised.clone(), stop, kill).await;
+            supervised.dead.store(true, Ordering::SeqCst);
+        });
 
         Ok(Self {
-            addr: port.to_socket_addr(),
-            process,
+            status,
+            in_flight: AtomicUsize::new(0),
+            idle: Notify::new(),
+            connections,
+            next_connection: AtomicUsize::new(0),
+            control: Mutex::new(None),
+            supervisor: std::sync::Mutex::new(Some(supervisor)),
+            unresponsive,
+            probing: AtomicBool::new(false),
+            ping_timeout,
+            breaker,
         })
     }
 
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn release(&self) {
+        if self.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.idle.notify();
+        }
+    }
+
+    // Resolves once there are no in-flight renders.
+    pub async fn wait_idle(&self) {
+        while self.in_flight() > 0 {
+            self.idle.notified().await;
+        }
+    }
+
+    // Closes connections and asks the process to exit. Resolves once the process is gone.
+    pub async fn shutdown(&self, deadline: time::Instant) {
+        // Js worker can close its server only when there are no open connections left
+        for connection in &self.connections {
+            connection.lock().await.take();
+        }
+        self.control.lock().await.take();
+        let supervisor = self
+            .supervisor
+            .lock()
+            .expect("Supervisor lock is poisoned")
+            .take();
+        if let Some(supervisor) = supervisor {
+            let (done, wait) = oneshot::channel();
+            if supervisor.send(Shutdown { deadline, done }).is_ok() {
+                wait.await.ok();
+            }
+        }
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        match self.status.pid.load(Ordering::SeqCst) {
+            0 => None,
+            pid => Some(pid),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.pid().is_some()
+    }
+
+    // Whether renders can be dispatched to the worker, i.e. it's ready and its circuit is not open.
+    pub fn is_available(&self) -> bool {
+        let is_open = match &self.breaker {
+            Some(breaker) => breaker.is_open(),
+            None => false,
+        };
+        self.is_ready() && !is_open
+    }
+
+    // Lets a render through the circuit breaker. `Err` means the circuit is open, while `None`
+    // means there's no circuit breaker.
+    pub fn attempt(&self) -> Result<Option<Attempt>, RenderingError> {
+        match &self.breaker {
+            Some(breaker) => match breaker.attempt() {
+                Some(attempt) => Ok(Some(attempt)),
+                None => Err(RenderingError::CircuitOpen),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // Pings the process over the control connection. Resolves with the round-trip time.
+    pub async fn ping(&self) -> Result<Duration, RenderingError> {
+        let started_at = Instant::now();
+        let connection = match self.open(&self.control).await {
+            Ok(connection) => connection,
+            Err(err) => return Err(RenderingError::ConnectionError(err)),
+        };
+        let request_id = Uuid::new_v4();
+        let frame = protocol::encode_frame(protocol::FRAME_PING, &request_id, &[])?;
+        let mut exchange = connection.start(request_id, frame, None).await?;
+        match exchange.next_frame().await? {
+            Frame::Pong => Ok(started_at.elapsed()),
+            Frame::Head(_) | Frame::Chunk(_) | Frame::End | Frame::Error(_) => Err(
+                RenderingError::InvalidResponse("unexpected response to ping".to_string()),
+            ),
+        }
+    }
+
+    // Pings the process after a render timed out and kills it if it doesn't respond in time, so
+    // the supervisor restarts it. A JS renderer stuck in a synchronous loop blocks the event loop
+    // of the process, so it can't cancel the render, and every later render dispatched to it would
+    // time out as well. The ping isn't queued behind renders, so a missed pong means the process
+    // is stuck rather than busy.
+    pub fn probe(self: Arc<Self>) {
+        if self.probing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        tokio::spawn(async move {
+            let pid = self.pid();
+            match time::timeout(self.ping_timeout, self.ping()).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => warn!("{worker}: Ping failed: {err}", worker = self, err = err),
+                Err(_) => {
+                    if let Some(pid) = pid {
+                        error!(
+                            "{worker}: Process is unresponsive after a timed out render, killing it",
+                            worker = self,
+                        );
+                        self.unresponsive.send(pid).ok();
+                    }
+                }
+            }
+            self.probing.store(false, Ordering::SeqCst);
+        });
+    }
+
+    pub async fn health(&self, ping_timeout: Duration) -> WorkerHealth {
+        let pid = self.pid();
+        let (state, latency) = if self.status.dead.load(Ordering::SeqCst) {
+            (WorkerState::Dead, None)
+        } else if pid.is_none() {
+            (WorkerState::Starting, None)
+        } else {
+            match time::timeout(ping_timeout, self.ping()).await {
+                Ok(Ok(latency)) if self.is_available() => (WorkerState::Ready, Some(latency)),
+                Ok(Ok(latency)) => (WorkerState::Degraded, Some(latency)),
+                Ok(Err(err)) => {
+                    warn!("{worker}: Ping failed: {err}", worker = self, err = err);
+                    (WorkerState::Degraded, None)
+                }
+                Err(_) => {
+                    warn!(
+                        "{worker}: Ping timed out after {timeout}ms",
+                        worker = self,
+                        timeout = ping_timeout.as_millis()
+                    );
+                    (WorkerState::Degraded, None)
+                }
+            }
+        };
+        WorkerHealth {
+            state,
+            pid,
+            restarts: self.status.restarts.load(Ordering::SeqCst),
+            uptime: pid.map(|_| self.status.started_at().elapsed()),
+            in_flight: self.in_flight(),
+            latency,
+        }
+    }
+
     pub fn display(&self) -> String {
         format!(
-            "[RS] Worker [id: {} port: {}]",
-            self.process.id(),
-            self.addr.port()
+            "[RS] Worker [id: {} {}]",
+            self.status.pid.load(Ordering::SeqCst),
+            self.status.addr()
         )
     }
 
     pub fn display_with_request_id(&self, request_id: &Uuid) -> String {
         format!(
-            "[RS] Worker [id: {} port: {} request: {}]",
-            self.process.id(),
-            self.addr.port(),
+            "[RS] Worker [id: {} {} request: {}]",
+            self.status.pid.load(Ordering::SeqCst),
+            self.status.addr(),
             request_id
         )
     }
 
-    pub async fn connect(&self) -> Result<TcpStream, io::Error> {
+    // Waits for the process to exit and respawns it according to the restart policy.
+    // Returns when the worker handle gets dropped or when the policy doesn't allow restarts.
+    async fn supervise(
+        idx: usize,
+        addr: Addr,
+        mut process: Child,
+        cfg: Arc<WorkerConfig>,
+        status: Arc<Status>,
+        mut stop: oneshot::Receiver<Shutdown>,
+        mut kill: mpsc::UnboundedReceiver<u32>,
+    ) {
+        let mut attempt = 0;
+        loop {
+            let started_at = Instant::now();
+            let pid = process.id();
+            let exit_status = loop {
+                tokio::select! {
+                    res = &mut process => break res.ok(),
+                    shutdown = &mut stop => {
+                        if let Ok(shutdown) = shutdown {
+                            Self::terminate(&mut process, &status, shutdown.deadline).await;
+                            shutdown.done.send(()).ok();
+                        }
+                        return;
+                    },
+                    // A request might concern a process which has been restarted already
+                    Some(unresponsive) = kill.recv() => {
+                        if unresponsive == pid {
+                            Self::kill(&mut process, &status);
+                            break (&mut process).await.ok();
+                        }
+                    },
+                }
+            };
+            status.pid.store(0, Ordering::SeqCst);
+
+            match exit_status {
+                Some(exit_status) => error!(
+                    "[RS] Worker [id: {pid} {addr}]: Process exited with {status}",
+                    pid = pid,
+                    addr = status.addr(),
+                    status = exit_status
+                ),
+                None => error!(
+                    "[RS] Worker [id: {pid} {addr}]: Process exited with unknown status",
+                    pid = pid,
+                    addr = status.addr(),
+                ),
+            }
+            cfg.emit(WorkerEvent::Exited {
+                worker: idx,
+                pid,
+                status: exit_status,
+            });
+
+            let (initial_delay, max_delay) = match cfg.restart_policy {
+                RestartPolicy::Never => return,
+                RestartPolicy::Backoff {
+                    initial_delay,
+                    max_delay,
+                } => (initial_delay, max_delay),
+            };
+
+            // If the process has been running long enough, consider it healthy and start
+            // the backoff from scratch.
+            if started_at.elapsed() >= max_delay {
+                attempt = 0;
+            }
+
+            loop {
+                let delay = cmp::min(
+                    initial_delay
+                        .checked_mul(1 << cmp::min(attempt, 16))
+                        .unwrap_or(max_delay),
+                    max_delay,
+                );
+                attempt += 1;
+                warn!(
+                    "[RS] Worker [{addr}]: Restarting in {delay}ms. Attempt: {attempt}",
+                    addr = status.addr(),
+                    delay = delay.as_millis(),
+                    attempt = attempt
+                );
+                tokio::select! {
+                    _ = time::delay_for(delay) => {},
+                    _ = &mut stop => return,
+                };
+                let next = tokio::select! {
+                    res = Process::start(&addr, &cfg) => res,
+                    _ = &mut stop => return,
+                };
+                match next {
+                    Ok((next, next_addr)) => {
+                        process = next;
+                        status.set_addr(next_addr);
+                        status.set_started_at(Instant::now());
+                        let restarts = status.restarts.fetch_add(1, Ordering::SeqCst) + 1;
+                        status.pid.store(process.id(), Ordering::SeqCst);
+                        info!(
+                            "[RS] Worker [id: {pid} {addr}]: Restarted",
+                            pid = process.id(),
+                            addr = status.addr(),
+                        );
+                        cfg.emit(WorkerEvent::Restarted {
+                            worker: idx,
+                            pid: process.id(),
+                            restarts,
+                        });
+                        break;
+                    }
+                    Err(err) => {
+                        error!(
+                            "[RS] Worker [{addr}]: Failed to restart: {err}",
+                            addr = status.addr(),
+                            err = err
+                        );
+                        cfg.emit(WorkerEvent::RestartFailed {
+                            worker: idx,
+                            attempt,
+                            error: err,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    // Sends SIGTERM to the process and waits for it to exit, falling back to SIGKILL once
+    // the deadline is exceeded.
+    async fn terminate(process: &mut Child, status: &Status, deadline: time::Instant) {
+        let pid = process.id();
+        status.pid.store(0, Ordering::SeqCst);
+        info!(
+            "[RS] Worker [id: {pid} {addr}]: Shutting down",
+            pid = pid,
+            addr = status.addr(),
+        );
+
+        #[cfg(unix)]
+        // Safety: the process is not reaped until it's awaited below, so the pid can't be reused
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+
+        match time::timeout_at(deadline, &mut *process).await {
+            Ok(_) => info!(
+                "[RS] Worker [id: {pid} {addr}]: Process exited",
+                pid = pid,
+                addr = status.addr(),
+            ),
+            Err(_) => {
+                warn!(
+                    "[RS] Worker [id: {pid} {addr}]: Process didn't exit in time, killing it",
+                    pid = pid,
+                    addr = status.addr(),
+                );
+                Self::kill(process, status);
+                process.await.ok();
+            }
+        }
+    }
+
+    fn kill(process: &mut Child, status: &Status) {
+        if let Err(err) = process.kill() {
+            error!(
+                "[RS] Worker [id: {pid} {addr}]: Failed to kill process: {err}",
+                pid = process.id(),
+                addr = status.addr(),
+                err = err
+            );
+        }
+    }
+
+    // Returns one of the persistent connections for renders in a round-robin order.
+    pub async fn connection(&self) -> Result<Arc<Connection>, io::Error> {
+        let idx = self.next_connection.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+        self.open(&self.connections[idx]).await
+    }
+
+    // Returns the connection in the slot. It's (re)established if it's not open yet or got closed,
+    // e.g. due to a restart of the worker.
+    async fn open(
+        &self,
+        slot: &Mutex<Option<Arc<Connection>>>,
+    ) -> Result<Arc<Connection>, io::Error> {
+        let mut slot = slot.lock().await;
+        if let Some(connection) = &*slot {
+            if !connection.is_closed() {
+                return Ok(connection.clone());
+            }
+        }
+        let connection = Arc::new(self.connect().await?);
+        *slot = Some(connection.clone());
+        Ok(connection)
+    }
+
+    async fn connect(&self) -> Result<Connection, io::Error> {
         let max_attempts = 5;
         let mut attempt = 1;
         loop {
@@ -137,10 +779,10 @@ impl Worker {
                     time::delay_for(std::time::Duration::from_millis(delay)).await
                 }
             }
-            match TcpStream::connect(self.addr).await {
-                Ok(stream) => {
+            match self.status.addr().connect(self.display()).await {
+                Ok(connection) => {
                     trace!("{worker}: Connected to the js worker", worker = self);
-                    return Ok(stream);
+                    return Ok(connection);
                 }
                 Err(err) => match err.kind() {
                     io::ErrorKind::ConnectionRefused => {
@@ -171,6 +813,15 @@ impl Worker {
     }
 }
 
+impl Drop for Worker {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Addr::Unix(path) = self.status.addr() {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
 impl fmt::Display for Worker {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.display())
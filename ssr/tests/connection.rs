@@ -0,0 +1,61 @@
+use std::{path::PathBuf, time::Duration};
+
+use futures_util::stream::StreamExt;
+use http::Uri;
+use ssr::{JsRenderer, RenderingError, Ssr, SsrConfig};
+use tokio::time;
+
+fn fixture(path: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(path)
+}
+
+fn uri(path: &str) -> Uri {
+    path.parse::<Uri>().unwrap()
+}
+
+#[tokio::test]
+async fn unconsumed_stream_doesnt_stall_connection() {
+    let ssr = Ssr::new(SsrConfig {
+        port: 0,
+        js_worker: fixture("js/worker.js"),
+        global_js_renderer: Some(fixture("tests/fixtures/renderer.js")),
+        connections_per_worker: 1,
+        render_timeout: Some(Duration::from_secs(10)),
+        ..SsrConfig::default()
+    })
+    .await
+    .unwrap();
+
+    // The stream is never polled while the worker keeps rendering it
+    let mut stream = ssr
+        .render_stream(&uri("/flood"), &(), JsRenderer::Global)
+        .await
+        .unwrap();
+    time::delay_for(Duration::from_millis(200)).await;
+
+    let uri = uri("/");
+    let render = ssr.render(&uri, &(), JsRenderer::Global);
+    match time::timeout(Duration::from_secs(5), render).await {
+        Ok(Ok(body)) => assert_eq!(body, "ok"),
+        Ok(Err(err)) => panic!("Render failed: {}", err),
+        Err(_) => panic!("Render is stalled by the unconsumed stream"),
+    }
+    let health = ssr.health().await;
+    assert!(health.is_ready());
+    assert_eq!(health.workers[0].in_flight, 0);
+
+    // Chunks received before the backlog was exceeded are still delivered
+    let mut received = 0;
+    let err = loop {
+        match stream.next().await {
+            Some(Ok(chunk)) => received += chunk.len(),
+            Some(Err(err)) => break err,
+            None => panic!("Stream is complete after {} bytes", received),
+        }
+    };
+    assert!(received > 0);
+    match err {
+        RenderingError::BacklogExceeded(_) => {}
+        err => panic!("Expected exceeded backlog, got {:?}", err),
+    }
+}
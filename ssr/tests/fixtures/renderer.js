@@ -0,0 +1,19 @@
+module.exports.render = ({url}) => {
+  if (url.path === "/stuck") {
+    while (true) {}
+  }
+  if (url.path === "/slow") {
+    return new Promise(resolve => setTimeout(() => resolve("slow"), 1000));
+  }
+  if (url.path === "/flood") {
+    const chunk = "x".repeat(1 << 20);
+    return {
+      body: (async function* () {
+        for (let i = 0; i < 64; i++) {
+          yield chunk;
+        }
+      })(),
+    };
+  }
+  return "ok";
+};
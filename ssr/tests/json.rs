@@ -0,0 +1,163 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use proptest::prelude::*;
+use serde_json::{json, Map, Value};
+use ssr::json::{self, Escaping};
+
+// Arbitrary strings mixed with the ones which are likely to break out of a script element
+fn string() -> impl Strategy<Value = String> {
+    let fragment = prop_oneof![
+        any::<String>(),
+        Just("</script>".to_string()),
+        Just("</SCRIPT ".to_string()),
+        Just("<!--".to_string()),
+        Just("-->".to_string()),
+        Just("\u{2028}".to_string()),
+        Just("\u{2029}".to_string()),
+        Just("\"'&<>\\".to_string()),
+        Just("&quot;".to_string()),
+    ];
+    prop::collection::vec(fragment, 0..4).prop_map(|fragments| fragments.concat())
+}
+
+fn value() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::Bool),
+        any::<i64>().prop_map(Value::from),
+        string().prop_map(Value::String),
+    ];
+    leaf.prop_recursive(4, 64, 8, |value| {
+        prop_oneof![
+            prop::collection::vec(value.clone(), 0..8).prop_map(Value::Array),
+            prop::collection::vec((string(), value), 0..8)
+                .prop_map(|entries| Value::Object(entries.into_iter().collect::<Map<_, _>>())),
+        ]
+    })
+}
+
+// Nothing can terminate a script element or open a comment in it without `<`
+fn assert_script_safe(json: &str) {
+    assert!(!json.contains('<'), "{}", json);
+    assert!(!json.contains('\u{2028}'), "{}", json);
+    assert!(!json.contains('\u{2029}'), "{}", json);
+}
+
+// Browser decodes character references once the attribute is parsed
+fn decode_attribute(json: &str) -> String {
+    assert!(!json.contains(&['"', '\'', '<', '>'][..]), "{}", json);
+    assert!(!json.replace("&quot;", "").contains('&'), "{}", json);
+    json.replace("&quot;", "\"")
+}
+
+proptest! {
+    #[test]
+    fn script_tag_round_trips(value in value()) {
+        let json = json::to_script_safe_string(&value).unwrap();
+        assert_script_safe(&json);
+        prop_assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), value);
+    }
+
+    #[test]
+    fn html_attribute_round_trips(value in value()) {
+        let json = json::to_string(&value, Escaping::HtmlAttribute).unwrap();
+        let json = decode_attribute(&json);
+        assert_script_safe(&json);
+        prop_assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), value);
+    }
+
+    #[test]
+    fn non_string_keys_round_trip(value in prop::collection::btree_map(any::<i64>(), string(), 0..8)) {
+        let json = json::to_script_safe_string(&value).unwrap();
+        assert_script_safe(&json);
+        prop_assert_eq!(&serde_json::from_str::<BTreeMap<i64, String>>(&json).unwrap(), &value);
+
+        let json = json::to_string(&value, Escaping::HtmlAttribute).unwrap();
+        let json = decode_attribute(&json);
+        prop_assert_eq!(&serde_json::from_str::<BTreeMap<i64, String>>(&json).unwrap(), &value);
+    }
+
+    #[test]
+    fn plain_json_is_not_escaped(value in value()) {
+        let json = json::to_string(&value, Escaping::PlainJson).unwrap();
+        prop_assert_eq!(json, serde_json::to_string(&value).unwrap());
+    }
+}
+
+#[test]
+fn escapes_html_sensitive_characters() {
+    let value = "<>&\u{2028}\u{2029}";
+    assert_eq!(
+        json::to_script_safe_string(value).unwrap(),
+        r#""\u003c\u003e\u0026\u2028\u2029""#
+    );
+    assert_eq!(
+        json::to_string(value, Escaping::HtmlAttribute).unwrap(),
+        r#"&quot;\u003c\u003e\u0026\u2028\u2029&quot;"#
+    );
+    assert_eq!(
+        json::to_string("'\"\\", Escaping::HtmlAttribute).unwrap(),
+        r#"&quot;\u0027\u0022\\&quot;"#
+    );
+}
+
+// Evaluates each line as a JS expression the way it's inlined into a script, and as JSON, in
+// Node.js, and returns what it evaluates to
+fn evaluate(lines: &[String]) -> Vec<Value> {
+    let script = r#"
+        const lines = require("fs").readFileSync(0, "utf8").split("\n").filter(Boolean);
+        for (const line of lines) {
+            const value = (0, eval)("(" + line + ")");
+            if (JSON.stringify(value) !== JSON.stringify(JSON.parse(line))) {
+                throw new Error("JSON.parse and eval disagree on " + line);
+            }
+            console.log(JSON.stringify(value));
+        }
+    "#;
+    let mut node = Command::new("node")
+        .arg("-e")
+        .arg(script)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Node.js is not installed");
+    let mut stdin = node.stdin.take().unwrap();
+    stdin.write_all(lines.join("\n").as_bytes()).unwrap();
+    drop(stdin);
+    let output = node.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn node_evaluates_escaped_json_to_the_same_value() {
+    let values = [
+        json!("</script><script>alert(1)</script>"),
+        json!("<!-- <script> -->"),
+        json!("\u{2028}\u{2029}"),
+        json!({ "a&b": "\"'&<>\\", "nested": ["&quot;", "\u{0}", "\n\r\t"] }),
+        json!([null, true, -1, 1.5, "ünï 😀"]),
+    ];
+    let lines: Vec<String> = values
+        .iter()
+        .flat_map(|value| {
+            let script = json::to_script_safe_string(value).unwrap();
+            let attribute = json::to_string(value, Escaping::HtmlAttribute).unwrap();
+            vec![script, decode_attribute(&attribute)]
+        })
+        .collect();
+    let evaluated = evaluate(&lines);
+    let expected: Vec<Value> = values
+        .iter()
+        .flat_map(|value| vec![value.clone(), value.clone()])
+        .collect();
+    assert_eq!(evaluated, expected);
+}
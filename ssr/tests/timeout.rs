@@ -0,0 +1,72 @@
+use std::{path::PathBuf, time::Duration};
+
+use http::Uri;
+use ssr::{JsRenderer, RenderingError, RestartPolicy, Ssr, SsrConfig};
+use tokio::time::{self, Instant};
+
+fn fixture(path: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(path)
+}
+
+async fn render(ssr: &Ssr, path: &str) -> Result<String, RenderingError> {
+    let uri = path.parse::<Uri>().unwrap();
+    ssr.render(&uri, &(), JsRenderer::Global).await
+}
+
+async fn start() -> Ssr {
+    Ssr::new(SsrConfig {
+        port: 0,
+        js_worker: fixture("js/worker.js"),
+        global_js_renderer: Some(fixture("tests/fixtures/renderer.js")),
+        render_timeout: Some(Duration::from_millis(300)),
+        ping_timeout: Duration::from_millis(200),
+        restart_policy: RestartPolicy::Backoff {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(1),
+        },
+        ..SsrConfig::default()
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn worker_stuck_in_sync_loop_is_restarted() {
+    let ssr = start().await;
+
+    match render(&ssr, "/stuck").await {
+        Err(RenderingError::Timeout(_)) => {}
+        res => panic!("Expected timeout, got {:?}", res),
+    }
+
+    // The worker gets killed once it doesn't respond to a ping, and restarted by the supervisor
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match render(&ssr, "/").await {
+            Ok(body) => {
+                assert_eq!(body, "ok");
+                break;
+            }
+            Err(_) if Instant::now() < deadline => {
+                time::delay_for(Duration::from_millis(100)).await;
+            }
+            Err(err) => panic!("Worker is not restarted: {}", err),
+        }
+    }
+    assert_eq!(ssr.health().await.workers[0].restarts, 1);
+}
+
+#[tokio::test]
+async fn worker_busy_with_async_render_is_not_restarted() {
+    let ssr = start().await;
+
+    match render(&ssr, "/slow").await {
+        Err(RenderingError::Timeout(_)) => {}
+        res => panic!("Expected timeout, got {:?}", res),
+    }
+
+    // The worker responds to the ping after the timed out render, so it keeps running
+    time::delay_for(Duration::from_millis(500)).await;
+    assert_eq!(render(&ssr, "/").await.unwrap(), "ok");
+    assert_eq!(ssr.health().await.workers[0].restarts, 0);
+}